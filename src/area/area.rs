@@ -1,4 +1,4 @@
-use crate::area::column::Column;
+use crate::area::column::{Column, along, across, union};
 use crate::area::Point;
 use crate::traits::HasRectangle;
 use image::imageops::ColorMap;
@@ -6,18 +6,24 @@ use image::math::Rect;
 use image::ColorType;
 use std::cell::RefCell;
 use std::rc::{Rc, Weak};
+use nalgebra::DMatrix;
 
 #[derive(Debug, Clone)]
 pub struct Area {
     rect: Rect,
     pixels: u32,
     pub(crate) punctuation: bool,
+    pub(crate) punctuation_character: Option<char>,
     changed: bool,
     pub(crate) splitted: bool,
     pub(crate) min_rgb: u32,
     pub(crate) column: Weak<RefCell<Column>>,
     remove: bool,
     source_areas: Vec<Area>,
+    /// Black pixels inside `rect`.
+    mask: DMatrix<bool>,
+    /// Area was found in a white on black region, its image must be inverted before OCR.
+    pub(crate) inverted: bool,
 }
 
 impl HasRectangle for Area {
@@ -35,20 +41,88 @@ impl HasRectangle for Area {
 }
 
 impl Area {
+    /// Creates area whose every pixel is black.
     pub fn new(rect: Rect, pixels: u32) -> Self {
+        let mask = DMatrix::from_element(rect.height as usize, rect.width as usize, true);
+        Self::with_mask(rect, pixels, mask)
+    }
+
+    /// Creates area from its black pixels. Mask has the dimensions of `rect`.
+    pub(crate) fn from_mask(rect: Rect, mask: DMatrix<bool>) -> Self {
+        let pixels = mask.iter().filter(|&&black| black).count() as u32;
+        Self::with_mask(rect, pixels, mask)
+    }
+
+    fn with_mask(rect: Rect, pixels: u32, mask: DMatrix<bool>) -> Self {
         Self {
             rect,
             pixels,
             punctuation: false,
+            punctuation_character: None,
             changed: false,
             splitted: false,
             min_rgb: Default::default(),
             column: Weak::new(),
             remove: false,
             source_areas: vec![],
+            mask,
+            inverted: false,
         }
     }
 
+    /// Checks if pixel at image coordinates belongs to this area.
+    pub(crate) fn is_black(&self, x: u32, y: u32) -> bool {
+        x >= self.rect.x && y >= self.rect.y && x < self.rect.x + self.rect.width && y < self.rect.y + self.rect.height
+            && self.mask[((y - self.rect.y) as usize, (x - self.rect.x) as usize)]
+    }
+
+    /// Moves area by the offset, used to move areas found in a tile to image coordinates.
+    pub(crate) fn translate(&mut self, dx: u32, dy: u32) {
+        self.rect.x += dx;
+        self.rect.y += dy;
+    }
+
+    /// Checks if both areas contain the same black pixel.
+    pub(crate) fn shares_pixels(&self, other: &Area) -> bool {
+        let x = self.rect.x.max(other.rect.x);
+        let y = self.rect.y.max(other.rect.y);
+        let end_x = (self.rect.x + self.rect.width).min(other.rect.x + other.rect.width);
+        let end_y = (self.rect.y + self.rect.height).min(other.rect.y + other.rect.height);
+
+        (y..end_y).any(|y| (x..end_x).any(|x| self.is_black(x, y) && other.is_black(x, y)))
+    }
+
+    /// Grows the area to cover `other` and adds its black pixels to the mask. Pixels
+    /// that belong to both areas are counted once.
+    pub(crate) fn add_pixels(&mut self, other: &Area) {
+        let rect = union(&self.rect, &other.rect);
+        let mut mask = DMatrix::from_element(rect.height as usize, rect.width as usize, false);
+
+        for area in [&*self, other].iter() {
+            let (dx, dy) = ((area.rect.x - rect.x) as usize, (area.rect.y - rect.y) as usize);
+            for row in 0..area.mask.nrows() {
+                for col in 0..area.mask.ncols() {
+                    if area.mask[(row, col)] {
+                        mask[(row + dy, col + dx)] = true;
+                    }
+                }
+            }
+        }
+
+        // Larger part decides the color of the merged area
+        if other.pixels > self.pixels {
+            self.inverted = other.inverted;
+        }
+        self.pixels = mask.iter().filter(|&&black| black).count() as u32;
+        self.rect = rect;
+        self.mask = mask;
+        self.min_rgb = self.min_rgb.min(other.min_rgb);
+    }
+
+    pub fn get_pixels(&self) -> u32 {
+        self.pixels
+    }
+
     pub fn get_size(&self) -> u32 {
         self.rect.width * self.rect.height
     }
@@ -92,6 +166,96 @@ impl Area {
     pub(crate) fn get_min_dim(&self) -> u32 {
         self.rect.width.min(self.rect.height)
     }
+
+    pub(crate) fn set_punctuation(&mut self, character: char) {
+        self.punctuation = true;
+        self.punctuation_character = Some(character);
+    }
+
+    pub(crate) fn get_punctuation(&self) -> Option<char> {
+        self.punctuation_character
+    }
+
+    /// Combines other area into this one. Original areas are kept in `source_areas`.
+    pub(crate) fn merge(&mut self, other: Area) {
+        if self.source_areas.is_empty() {
+            let mut original = self.clone();
+            original.source_areas = vec![];
+            self.source_areas.push(original);
+        }
+
+        self.add_pixels(&other);
+        self.changed = true;
+
+        if other.source_areas.is_empty() {
+            self.source_areas.push(other);
+        } else {
+            self.source_areas.extend(other.source_areas);
+        }
+    }
+
+    /// Splits the area in reading direction at `cuts`. Pieces are shrunk to the black pixels
+    /// they contain and empty pieces are dropped. The original area is kept in `source_areas`
+    /// of every piece.
+    pub(crate) fn split(&self, cuts: &[u32], vertical: bool) -> Vec<Area> {
+        let (along_start, along_end) = along(&self.rect, vertical);
+        let (across_start, across_end) = across(&self.rect, vertical);
+
+        let mut bounds = vec![along_start];
+        bounds.extend(cuts.iter().copied().filter(|&c| c > along_start && c < along_end));
+        bounds.push(along_end);
+
+        let mut original = self.clone();
+        original.source_areas = vec![];
+
+        let mut pieces = vec![];
+        for range in bounds.windows(2) {
+            let mut min = (u32::max_value(), u32::max_value());
+            let mut max = (0, 0);
+            let mut pixels = 0;
+
+            for a in range[0]..range[1] {
+                for c in across_start..across_end {
+                    let (x, y) = if vertical { (c, a) } else { (a, c) };
+                    if !self.is_black(x, y) {
+                        continue;
+                    }
+
+                    min = (min.0.min(x), min.1.min(y));
+                    max = (max.0.max(x), max.1.max(y));
+                    pixels += 1;
+                }
+            }
+
+            if pixels == 0 {
+                continue;
+            }
+
+            let rect = Rect {
+                x: min.0,
+                y: min.1,
+                width: max.0 - min.0 + 1,
+                height: max.1 - min.1 + 1
+            };
+            let mask = DMatrix::from_fn(rect.height as usize, rect.width as usize, |row, col| {
+                let (x, y) = (rect.x + col as u32, rect.y + row as u32);
+                let (a, _) = if vertical { (y, x) } else { (x, y) };
+                a >= range[0] && a < range[1] && self.is_black(x, y)
+            });
+            let mut piece = Area::with_mask(rect, pixels, mask);
+            piece.min_rgb = self.min_rgb;
+            piece.inverted = self.inverted;
+            piece.splitted = true;
+            piece.source_areas = vec![original.clone()];
+            pieces.push(piece);
+        }
+
+        pieces
+    }
+
+    pub(crate) fn get_source_areas(&self) -> &[Area] {
+        &self.source_areas
+    }
 }
 
 #[cfg(test)]
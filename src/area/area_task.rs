@@ -1,13 +1,21 @@
-use crate::area::{Area, Column, Point};
-use crate::util::{sharpen_image, crop, make_bw, matrix_from_image};
-use image::{DynamicImage, ImageBuffer, Pixel, SubImage, GenericImage, Luma, GrayImage, FromColor, Rgba};
+use crate::area::{Area, Column, ColumnList, Point};
+use crate::area::column::{along, furigana_span, find_region, intersects, union_regions};
+use crate::area::deskew;
+use crate::preprocess::Pipeline;
+use crate::util::{crop, make_bw, matrix_from_image};
+use image::{DynamicImage, ImageBuffer, Pixel, GenericImage, Luma, GrayImage, FromColor, Rgba, RgbaImage};
+use image::imageops::FilterType;
 use std::convert::Infallible;
 use std::ops::{Deref, DerefMut};
 use image::math::Rect;
-use crate::traits::{Step, Task};
+use crate::traits::{Step, Task, HasRectangle};
 use crate::error::KanjitomoError;
-use crate::{PARAMETERS, CharacterColor};
+use crate::CharacterColor;
+use crate::parameters::Parameters;
 use nalgebra::base::DMatrix;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::cell::RefCell;
 
 
 #[derive(Debug, Clone)]
@@ -17,29 +25,40 @@ where
 {
     width: u32,
     height: u32,
+    parameters: Arc<Parameters>,
     original_image: ImageBuffer<P, Vec<u8>>,
-    sharpened_image: Option<ImageBuffer<P, Vec<u8>>>,
+    /// Output of the preprocessing pipeline.
+    preprocessed_image: Option<GrayImage>,
     inverted: Option<DMatrix<bool>>,
     binary_image: Option<DMatrix<bool>>,
     background_image: Option<DMatrix<bool>>,
     border_pixels: Option<DMatrix<bool>>,
     areas: Option<Vec<Area>>,
-    columns: Option<Vec<Column>>,
-    vertical_columns: Option<Vec<Column>>,
-    horizontal_columns: Option<Vec<Column>>,
+    columns: Option<Vec<Rc<RefCell<Column>>>>,
+    vertical_columns: Option<Vec<Rc<RefCell<Column>>>>,
+    horizontal_columns: Option<Vec<Rc<RefCell<Column>>>>,
+    /// Angle in degrees the images were rotated by to straighten text lines.
+    skew_angle: Option<f32>,
+    /// Factor the original image was upscaled by because of small characters.
+    scale_factor: u32,
 }
 
 impl<P> AreaTask<P>
 where
     P: Pixel<Subpixel = u8> + 'static,
 {
-    pub fn new(target: ImageBuffer<P, Vec<u8>>) -> Self {
+    /// Areas smaller than this are noise and don't count towards character size.
+    const MIN_UPSCALE_AREA_SIZE: u32 = 3;
+    const MAX_UPSCALE_FACTOR: u32 = 4;
+
+    pub fn new(target: ImageBuffer<P, Vec<u8>>, parameters: Arc<Parameters>) -> Self {
         let (width, height) = target.dimensions();
         Self {
             width,
             height,
+            parameters,
             original_image: target,
-            sharpened_image: None,
+            preprocessed_image: None,
             inverted: None,
             binary_image: None,
             background_image: None,
@@ -48,57 +67,345 @@ where
             columns: None,
             vertical_columns: None,
             horizontal_columns: None,
+            skew_angle: None,
+            scale_factor: 1,
+        }
+    }
+
+    /// Runs the preprocessing pipeline on the original image. If the pipeline scales the image,
+    /// the original image is scaled by the same factor so that areas match it.
+    fn preprocess(&mut self, pipeline: &Pipeline) {
+        let original = &self.original_image;
+        let rgba = RgbaImage::from_fn(self.width, self.height, |x, y| original.get_pixel(x, y).to_rgba());
+        let mut preprocessed = pipeline.run(DynamicImage::ImageRgba8(rgba)).to_luma();
+
+        let factor = (preprocessed.width() / self.width).max(1);
+        if factor > 1 {
+            self.scale(factor);
+        }
+
+        if preprocessed.dimensions() != (self.width, self.height) {
+            preprocessed = image::imageops::resize(&preprocessed, self.width, self.height, FilterType::Nearest);
+        }
+
+        self.preprocessed_image = Some(preprocessed);
+    }
+
+    /// Scales the original image by `factor`.
+    fn scale(&mut self, factor: u32) {
+        self.width *= factor;
+        self.height *= factor;
+        self.scale_factor *= factor;
+        self.original_image = image::imageops::resize(&self.original_image, self.width, self.height, self.parameters.upscale_filter.into());
+    }
+
+    /// Estimates skew from the binary image and rotates the images to straighten it. Returns
+    /// `true` if the images were rotated and the binary image must be created again.
+    fn deskew(&mut self) -> bool {
+        let binary = self.binary_image.as_ref().expect("Skew can't be estimated before creating binary image!");
+        let angle = deskew::estimate_skew(binary, self.parameters.deskew_max_angle);
+        self.rotate(angle)
+    }
+
+    /// Rotates the images by `angle` degrees. Returns `true` if the images were rotated.
+    fn rotate(&mut self, angle: f32) -> bool {
+        self.skew_angle = Some(angle);
+
+        if angle == 0.0 {
+            return false;
+        }
+
+        log::debug!("Deskewing image by {} degrees", angle);
+        self.original_image = deskew::rotate(&self.original_image, angle);
+        self.preprocessed_image = self.preprocessed_image.as_ref().map(|preprocessed| deskew::rotate(preprocessed, angle));
+
+        true
+    }
+
+    /// Upscales the original image if characters are too small to keep their strokes in
+    /// binarization. Returns `true` if the image was scaled and detection must start over.
+    fn upscale(&mut self) -> bool {
+        let mut sizes: Vec<u32> = self.areas.iter()
+            .flatten()
+            .map(|area| area.get_max_dim())
+            .filter(|&size| size >= Self::MIN_UPSCALE_AREA_SIZE)
+            .collect();
+        if sizes.is_empty() {
+            return false;
+        }
+
+        sizes.sort();
+        let median = sizes[sizes.len() / 2];
+        if median >= self.parameters.upscale_max_character_size {
+            return false;
+        }
+
+        let factor = ((self.parameters.target_size as f32 / median as f32).ceil() as u32)
+            .max(2)
+            .min(Self::MAX_UPSCALE_FACTOR);
+        log::debug!("Upscaling image by {}, median character size is {}", factor, median);
+
+        self.width *= factor;
+        self.height *= factor;
+        self.scale_factor = factor;
+        self.original_image = image::imageops::resize(&self.original_image, self.width, self.height, self.parameters.upscale_filter.into());
+        self.preprocessed_image = None;
+        self.inverted = None;
+        self.binary_image = None;
+        self.background_image = None;
+        self.border_pixels = None;
+        self.areas = None;
+
+        true
+    }
+
+    /// Dimensions of the original image before upscaling.
+    fn source_dimensions(&self) -> (u32, u32) {
+        (self.width / self.scale_factor, self.height / self.scale_factor)
+    }
+
+    /// Maps point in the original image to the coordinates used by areas.
+    pub(crate) fn map_point(&self, point: Point) -> Point {
+        let (width, height) = self.source_dimensions();
+        let (mut x, mut y) = (point.x as f32, point.y as f32);
+
+        if let Some(angle) = self.skew_angle.filter(|&angle| angle != 0.0) {
+            let (deskewed_x, deskewed_y) = deskew::to_deskewed(x, y, angle, width, height);
+            x = deskewed_x;
+            y = deskewed_y;
+        }
+
+        let scale = self.scale_factor as f32;
+        Point {
+            x: (x * scale).max(0.0).round() as u32,
+            y: (y * scale).max(0.0).round() as u32,
+        }
+    }
+
+    /// Maps area rectangle back to the original image coordinates.
+    pub(crate) fn map_to_original(&self, rect: Rect) -> Rect {
+        let (width, height) = self.source_dimensions();
+        let scale = self.scale_factor;
+        let (x, y) = (rect.x / scale, rect.y / scale);
+        let rect = Rect {
+            x,
+            y,
+            width: (rect.x + rect.width + scale - 1) / scale - x,
+            height: (rect.y + rect.height + scale - 1) / scale - y,
+        };
+
+        match self.skew_angle {
+            Some(angle) if angle != 0.0 => deskew::map_rect(rect, angle, width, height),
+            _ => rect
         }
     }
 
-    pub(crate) fn get_subimages(&self, areas: Vec<Rect>) -> Vec<SubImage<ImageBuffer<P, Vec<u8>>>> {
-        let mut subimages = vec![];
-
-        for area in areas {
-
-        }
-
-        subimages
-    }
-    // fn collect_areas(&mut self) {
-    //     self.areas = vec![];
-    //     for column in &self.columns {
-    //         self.areas
-    //     }
-    // }
-    //
-    // pub(crate) fn get_area(&self, point: Point) -> Option<Area> {
-    //     let mut min_distance = 1_000_000_u32;
-    //     let mut closest_area: Option<Area> = None;
-    //
-    //     for area in &self.areas {
-    //         if area.punctuation {
-    //             continue;
-    //         }
-    //
-    //         let distance = area.get_midpoint().distance(&point) as u32;
-    //         if distance < min_distance {
-    //             min_distance = distance;
-    //             closest_area = Some(area.clone())
-    //         }
-    //     }
-    //
-    //     match closest_area {
-    //         None => None,
-    //         Some(area) => {
-    //             if min_distance > area.get_max_dim() {
-    //                 None
-    //             } else {
-    //                 Some(area)
-    //             }
-    //         }
-    //     }
-    // }
-
-    pub(crate) fn get_sub_images(&self, point: Point) -> Vec<SubImage<ImageBuffer<P, Vec<u8>>>> {
-        let sub_images = vec![];
-
-        sub_images
+    /// Rebuilds the area list from columns so that every area knows its column.
+    fn collect_areas(&mut self) {
+        let mut areas = vec![];
+
+        for column in self.columns.as_ref().expect("Areas can't be collected before finding columns!") {
+            let weak = Rc::downgrade(column);
+            let mut column = column.borrow_mut();
+            for area in column.areas.iter_mut() {
+                area.column = weak.clone();
+                areas.push(area.clone());
+            }
+        }
+
+        self.areas = Some(areas);
+    }
+
+    /// Finds the non-punctuation area closest to the point. Returns `None` if the point is
+    /// further away from the area center than the larger dimension of the area.
+    pub(crate) fn get_area(&self, point: Point) -> Option<Area> {
+        let point = self.map_point(point);
+        let mut min_distance = 1_000_000_u32;
+        let mut closest_area: Option<&Area> = None;
+
+        for area in self.areas.iter().flatten() {
+            if area.punctuation {
+                continue;
+            }
+
+            let distance = area.get_midpoint().distance(&point) as u32;
+            if distance < min_distance {
+                min_distance = distance;
+                closest_area = Some(area)
+            }
+        }
+
+        match closest_area {
+            None => None,
+            Some(area) => {
+                if min_distance > area.get_max_dim() {
+                    None
+                } else {
+                    Some(area.clone())
+                }
+            }
+        }
+    }
+
+    /// Areas in reading order starting from the area closest to the point. Reading continues
+    /// to the next column until `ocr_max_characters` characters have been found. Punctuation
+    /// is included but not counted.
+    pub(crate) fn get_areas(&self, point: Point) -> Vec<Area> {
+        let first = match self.get_area(point) {
+            None => return vec![],
+            Some(area) => area
+        };
+
+        let mut column = match first.column.upgrade() {
+            None => return vec![first],
+            Some(column) => column
+        };
+
+        let max_characters = self.parameters.ocr_max_characters as usize;
+        let mut areas = vec![];
+        let mut characters = 0;
+        let mut started = false;
+
+        loop {
+            let next = {
+                let column = column.borrow();
+                for area in &column.areas {
+                    if !started {
+                        if area.get_rectangle() != first.get_rectangle() {
+                            continue;
+                        }
+                        started = true;
+                    }
+
+                    if characters >= max_characters {
+                        break;
+                    }
+                    if !area.punctuation {
+                        characters += 1;
+                    }
+                    areas.push(area.clone());
+                }
+                column.get_next_column()
+            };
+
+            match next {
+                Some(next) if characters < max_characters => column = next,
+                _ => break
+            }
+        }
+
+        areas
+    }
+
+    /// Sub-images of characters starting from the area closest to the point.
+    pub(crate) fn get_sub_images(&self, point: Point) -> Vec<ImageBuffer<P, Vec<u8>>> {
+        self.get_areas(point)
+            .iter()
+            .filter(|area| !area.punctuation)
+            .map(|area| self.get_sub_image(area))
+            .collect()
+    }
+
+    /// Crops the area from the original image. Areas of white on black regions are inverted
+    /// so that OCR always gets black characters on white background.
+    pub(crate) fn get_sub_image(&self, area: &Area) -> ImageBuffer<P, Vec<u8>> {
+        let mut image = crop(&self.original_image, area.get_rectangle()).to_image();
+        if area.inverted {
+            image::imageops::invert(&mut image);
+        }
+
+        image
+    }
+
+    /// Furigana columns next to `areas`, together with the range of indices into `areas`
+    /// they are the reading of.
+    pub(crate) fn get_furigana(&self, areas: &[Area]) -> Vec<(Column, usize, usize)> {
+        let mut furigana = vec![];
+        let mut start = 0;
+
+        while start < areas.len() {
+            let mut end = start + 1;
+            while end < areas.len() && Weak::ptr_eq(&areas[start].column, &areas[end].column) {
+                end += 1;
+            }
+
+            if let Some(column) = areas[start].column.upgrade() {
+                let column = column.borrow();
+                let rects: Vec<Rect> = areas[start..end].iter().map(|a| a.get_rectangle()).collect();
+
+                for furigana_column in column.get_furigana_columns() {
+                    if let Some((span_start, span_end)) = furigana_span(&furigana_column.get_rectangle(), &rects, column.is_vertical()) {
+                        furigana.push((furigana_column.clone(), start + span_start, start + span_end));
+                    }
+                }
+            }
+
+            start = end;
+        }
+
+        furigana
+    }
+
+    fn find_areas(&mut self) {
+        let binary = self.binary_image.as_ref().expect("Areas can't be found before creating binary image!");
+        let areas = AreaFinder::new(&self.original_image, binary, self.inverted.as_ref(), self.border_pixels.as_ref()).run();
+        log::debug!("found {} areas", areas.len());
+        self.areas = Some(areas);
+    }
+
+    fn find_columns(&mut self) {
+        let areas = self.areas.as_ref().expect("Columns can't be found before finding areas!");
+        let (vertical, horizontal) = Column::find_columns(areas, &self.parameters);
+        log::debug!("found {} vertical and {} horizontal columns", vertical.len(), horizontal.len());
+
+        let vertical: Vec<_> = vertical.into_iter().map(|c| Rc::new(RefCell::new(c))).collect();
+        let horizontal: Vec<_> = horizontal.into_iter().map(|c| Rc::new(RefCell::new(c))).collect();
+
+        self.columns = Some(vertical.iter().chain(horizontal.iter()).cloned().collect());
+        self.vertical_columns = Some(vertical);
+        self.horizontal_columns = Some(horizontal);
+    }
+
+    fn find_furigana(&mut self) {
+        let vertical = Column::find_furigana(self.vertical_columns.take().expect("Furigana can't be found before finding columns!"));
+        let horizontal = Column::find_furigana(self.horizontal_columns.take().expect("Furigana can't be found before finding columns!"));
+
+        self.columns = Some(vertical.iter().chain(horizontal.iter()).cloned().collect());
+        self.vertical_columns = Some(vertical);
+        self.horizontal_columns = Some(horizontal);
+    }
+
+    fn merge_areas(&mut self) {
+        for column in self.columns.as_ref().expect("Areas can't be merged before finding columns!") {
+            column.borrow_mut().merge_areas();
+        }
+    }
+
+    fn split_areas(&mut self) {
+        for column in self.columns.as_ref().expect("Areas can't be split before finding columns!") {
+            column.borrow_mut().split_areas();
+        }
+    }
+
+    fn find_punctuation(&mut self) {
+        for column in self.columns.as_ref().expect("Punctuation can't be found before finding columns!") {
+            column.borrow_mut().find_punctuation();
+        }
+    }
+
+    /// Drops full size buffers once areas have been found. Areas carry their own masks.
+    fn release_buffers(&mut self) {
+        self.preprocessed_image = None;
+        self.inverted = None;
+        self.binary_image = None;
+        self.background_image = None;
+        self.border_pixels = None;
+    }
+
+    fn link_columns(&mut self) {
+        ColumnList::build(self.vertical_columns.as_ref().expect("Columns can't be linked before finding them!"));
+        ColumnList::build(self.horizontal_columns.as_ref().expect("Columns can't be linked before finding them!"));
+        self.collect_areas();
     }
 }
 
@@ -107,58 +414,239 @@ where
     P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
 {
     task: AreaTask<P>,
-    step: AreaTaskStep
+    step: AreaTaskStep,
+    pipeline: Arc<Pipeline>,
+    /// Tiles only go as far as finding areas.
+    tile: bool,
 }
 
 impl<'a, P> AreaDetector<P>
 where
     P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
 {
-    pub(crate) fn new(task: AreaTask<P>) -> Self {
-        Self { task, step: AreaTaskStep::SharpenImage }
+    pub(crate) fn new(task: AreaTask<P>, pipeline: Arc<Pipeline>) -> Self {
+        let tile_size = task.parameters.tile_size;
+        let step = if tile_size > 0 && (task.width > tile_size || task.height > tile_size) {
+            AreaTaskStep::FindAreasTiled
+        } else {
+            AreaTaskStep::Preprocess
+        };
+
+        Self { task, step, pipeline, tile: false }
     }
 
-    pub(crate) fn run(&mut self) -> AreaTask<P> {
+    /// Finds areas tile by tile, so that preprocessed and binary images are only allocated
+    /// for one tile at a time. Tiles overlap by `overlap` pixels. Areas inside the part of a
+    /// tile that no other tile covers are kept as they are, the rest are merged with their
+    /// copies and fragments from neighbouring tiles.
+    ///
+    /// Skew is estimated in every tile and the whole image is rotated by the median angle.
+    /// Upscaling is decided from the areas of all tiles. Returns `true` if the image was
+    /// rotated or upscaled and tiles must be detected again.
+    fn find_areas_tiled(&mut self, tile_size: u32, overlap: u32) -> bool {
+        let (width, height) = (self.task.width, self.task.height);
+        let estimate_skew = self.task.parameters.deskew && self.task.skew_angle.is_none();
+        let mut areas = vec![];
+        let mut shared = vec![];
+        let mut angles = vec![];
+        let mut factor = 1;
+
+        for core_y in (0..height).step_by(tile_size as usize) {
+            for core_x in (0..width).step_by(tile_size as usize) {
+                let core_end_x = (core_x + tile_size).min(width);
+                let core_end_y = (core_y + tile_size).min(height);
+                let (tile_x, tile_y) = (core_x.saturating_sub(overlap), core_y.saturating_sub(overlap));
+                let tile = Rect {
+                    x: tile_x,
+                    y: tile_y,
+                    width: (core_end_x + overlap).min(width) - tile_x,
+                    height: (core_end_y + overlap).min(height) - tile_y,
+                };
+
+                // Part of the tile that neighbouring tiles don't cover
+                let private_x = if core_x > 0 { core_x + overlap } else { 0 };
+                let private_y = if core_y > 0 { core_y + overlap } else { 0 };
+                let private_end_x = if core_end_x < width { core_end_x.saturating_sub(overlap) } else { width };
+                let private_end_y = if core_end_y < height { core_end_y.saturating_sub(overlap) } else { height };
+
+                let image = crop(&self.task.original_image, tile).to_image();
+                let mut task = AreaTask::new(image, self.task.parameters.clone());
+                if !estimate_skew {
+                    task.skew_angle = Some(0.0);
+                }
+                let mut detector = AreaDetector::new(task, self.pipeline.clone());
+                detector.step = AreaTaskStep::Preprocess;
+                detector.tile = true;
+
+                let task = detector.run();
+                // Pipeline scales every tile by the same factor
+                factor = task.scale_factor;
+                let tile_areas = task.areas.unwrap_or_default();
+                if estimate_skew && !tile_areas.is_empty() {
+                    angles.extend(task.skew_angle);
+                }
+
+                for mut area in tile_areas {
+                    area.translate(tile.x * factor, tile.y * factor);
+                    let rect = area.get_rectangle();
+                    if rect.x >= private_x * factor && rect.y >= private_y * factor
+                        && rect.x + rect.width <= private_end_x * factor && rect.y + rect.height <= private_end_y * factor {
+                        areas.push(area);
+                    } else {
+                        shared.push(area);
+                    }
+                }
+            }
+        }
+
+        // Strips covered by two neighbouring tiles, areas can only share pixels inside them
+        let mut strips = vec![];
+        for x in (tile_size..width).step_by(tile_size as usize) {
+            let start = x.saturating_sub(overlap);
+            strips.push(Rect { x: start * factor, y: 0, width: ((x + overlap).min(width) - start) * factor, height: height * factor });
+        }
+        for y in (tile_size..height).step_by(tile_size as usize) {
+            let start = y.saturating_sub(overlap);
+            strips.push(Rect { x: 0, y: start * factor, width: width * factor, height: ((y + overlap).min(height) - start) * factor });
+        }
+
+        log::debug!("found {} areas inside tiles and {} areas on tile borders", areas.len(), shared.len());
+        areas.extend(merge_tile_areas(shared, &strips));
+        self.task.areas = Some(areas);
+
+        if estimate_skew {
+            angles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let angle = angles.get(angles.len() / 2).cloned().unwrap_or(0.0);
+            if self.task.rotate(angle) {
+                return true;
+            }
+        }
+
+        if factor > 1 {
+            self.task.scale(factor);
+            return false;
+        }
+
+        self.task.parameters.upscale && self.task.scale_factor == 1 && self.task.upscale()
+    }
+
+    pub(crate) fn run(mut self) -> AreaTask<P> {
         use AreaTaskStep::*;
 
         loop {
             let next = match self.step {
-                SharpenImage => {
-                    self.task.sharpened_image = Some(sharpen_image(&self.task.original_image, None, None));
+                FindAreasTiled => {
+                    if self.find_areas_tiled(self.task.parameters.tile_size, self.task.parameters.tile_overlap) {
+                        FindAreasTiled
+                    } else {
+                        FindColumns
+                    }
+                },
+                Preprocess => {
+                    self.task.preprocess(&self.pipeline);
                     CreateBinaryImage
                 },
                 CreateBinaryImage => {
-                    let sharpened = self.task.sharpened_image.as_ref().expect("For some reason binary image was created before sharpening!");
-                    let bw = make_bw(sharpened, None);
+                    let preprocessed = self.task.preprocessed_image.as_ref().expect("For some reason binary image was created before preprocessing!");
+                    let bw = make_bw(preprocessed, 128);
                     self.task.binary_image = Some(matrix_from_image(&bw));
-                    InvertImage(InvertImageData::new())
+                    Deskew
+                },
+                Deskew => {
+                    let estimate = self.task.parameters.deskew && self.task.skew_angle.is_none();
+                    if estimate && self.tile {
+                        // Tiles only estimate skew, the whole image is rotated by the median angle
+                        let binary = self.task.binary_image.as_ref().expect("Skew can't be estimated before creating binary image!");
+                        self.task.skew_angle = Some(deskew::estimate_skew(binary, self.task.parameters.deskew_max_angle));
+                        InvertImage(InvertImageData::new())
+                    } else if estimate && self.task.deskew() {
+                        // Rotated images are binarized again, skew is estimated only once
+                        CreateBinaryImage
+                    } else {
+                        InvertImage(InvertImageData::new())
+                    }
                 },
                 InvertImage(ref mut inv_step) => {
                     inv_step.run(&mut self.task);
                     FindAreas
                 },
-                _ => FindAreas
+                FindAreas => {
+                    self.task.find_areas();
+                    if self.tile {
+                        Done
+                    } else {
+                        UpscaleImage
+                    }
+                },
+                UpscaleImage => {
+                    // Upscaled image goes through the whole detection again, but only once
+                    if self.task.parameters.upscale && self.task.scale_factor == 1 && self.task.upscale() {
+                        Preprocess
+                    } else {
+                        self.task.release_buffers();
+                        FindColumns
+                    }
+                },
+                FindColumns => {
+                    self.task.find_columns();
+                    FindFurigana
+                },
+                FindFurigana => {
+                    self.task.find_furigana();
+                    FindPunctuation
+                },
+                FindPunctuation => {
+                    // Punctuation is found before merging so that marks aren't merged into
+                    // the characters next to them
+                    self.task.find_punctuation();
+                    MergeAreas
+                },
+                MergeAreas => {
+                    self.task.merge_areas();
+                    SplitAreas
+                },
+                SplitAreas => {
+                    self.task.split_areas();
+                    // Marks that were merged from parts or split from touching characters
+                    self.task.find_punctuation();
+                    LinkColumns
+                },
+                LinkColumns => {
+                    self.task.link_columns();
+                    Done
+                },
+                Done => break
             };
             self.step = next;
         }
-        let mut task;
-        std::mem::replace(&mut task, self.task);
-        task
+
+        self.task
     }
 }
 
 pub(crate) enum AreaTaskStep
 {
-    SharpenImage,
+    FindAreasTiled,
+    Preprocess,
     CreateBinaryImage,
+    Deskew,
     InvertImage(InvertImageData),
-    FindAreas
+    FindAreas,
+    UpscaleImage,
+    FindColumns,
+    FindFurigana,
+    FindPunctuation,
+    MergeAreas,
+    SplitAreas,
+    LinkColumns,
+    Done
 }
 
 pub(crate) struct InvertImageData
 {
     width: u32,
     height: u32,
+    dark: Option<DMatrix<bool>>,
     visited: Option<DMatrix<bool>>,
     invert: Option<DMatrix<bool>>,
     neighbours_inverted: Option<DMatrix<u32>>
@@ -166,11 +654,20 @@ pub(crate) struct InvertImageData
 
 impl InvertImageData {
     const BLOCK_SIZE: u32 = 15;
+    /// Share of black pixels required before a block is considered to be dark background.
+    const DARK_BLOCK_RATIO: f32 = 0.7;
+    /// Connected dark regions smaller than this are left alone, they are most likely
+    /// thick strokes of large characters.
+    const MIN_INVERTED_BLOCKS: usize = 4;
+    /// Blocks surrounded by this many inverted blocks are inverted as well. Text inside
+    /// the region lowers black pixel ratio so these blocks aren't dark by themselves.
+    const MIN_INVERTED_NEIGHBOURS: u32 = 3;
 
     fn new() -> Self {
         Self {
             width: 0,
             height: 0,
+            dark: None,
             visited: None,
             invert: None,
             neighbours_inverted: None,
@@ -181,47 +678,655 @@ impl InvertImageData {
     where
         P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
     {
-        if PARAMETERS.fixed_black_level {
+        if task.parameters.fixed_black_level {
             ()
         } else {
-            match PARAMETERS.color_target {
+            match task.parameters.color_target {
                 CharacterColor::BlackOnWhite => (),
                 CharacterColor::Auto => {
                     self.detect_b_on_w(task)
                 },
-                CharacterColor::WhiteOnBlack => ()
+                CharacterColor::WhiteOnBlack => {
+                    Self::invert_all(task)
+                }
             }
         }
     }
 
+    fn invert_all<P>(task: &mut AreaTask<P>)
+    where
+        P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
+    {
+        let binary = task.binary_image.as_mut().expect("Binary image must be created before inverting it!");
+
+        for pixel in binary.iter_mut() {
+            *pixel = !*pixel;
+        }
+
+        task.inverted = Some(DMatrix::from_element(binary.nrows(), binary.ncols(), true));
+    }
+
+    /// Splits the image into blocks and looks for connected regions of dark blocks. These are
+    /// assumed to contain white text on black background and are inverted in the binary image.
     fn detect_b_on_w<P>(&mut self, task: &mut AreaTask<P>)
     where
         P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
     {
         self.width = (task.width as f32 / Self::BLOCK_SIZE as f32).ceil() as u32;
         self.height = (task.height as f32 / Self::BLOCK_SIZE as f32).ceil() as u32;
-        let bool_mx = DMatrix::from_row_slice(self.height as usize, self.width as usize, &vec![vec![false; self.width as usize]; self.height as usize].into_iter().flatten().collect::<Vec<bool>>()[..]);
+        let (rows, cols) = (self.height as usize, self.width as usize);
 
-        let visited = bool_mx.clone();
-        let invert = bool_mx;
-        let neighbours_inverted = DMatrix::from_row_slice(self.height as usize, self.width as usize, &vec![vec![0; self.width as usize]; self.height as usize].into_iter().flatten().collect::<Vec<u32>>()[..]);
-        task.border_pixels = Some(DMatrix::from_row_slice(task.height as usize, task.width as usize, &vec![vec![false; task.width as usize]; task.height as usize].into_iter().flatten().collect::<Vec<bool>>()[..]));
+        let binary = task.binary_image.as_ref().expect("Binary image must be created before inverting it!");
+        self.dark = Some(self.find_dark_blocks(binary));
+        self.visited = Some(DMatrix::from_element(rows, cols, false));
+        self.invert = Some(DMatrix::from_element(rows, cols, false));
+        self.neighbours_inverted = Some(DMatrix::from_element(rows, cols, 0));
 
-        for x in 0..self.width {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.check_block(x, y);
+            }
+        }
+
+        self.invert_surrounded_blocks();
+        self.invert_pixels(task);
+    }
+
+    fn find_dark_blocks(&self, binary: &DMatrix<bool>) -> DMatrix<bool> {
+        let (rows, cols) = (self.height as usize, self.width as usize);
+        let block_size = Self::BLOCK_SIZE as usize;
 
+        let mut black_pixels = DMatrix::from_element(rows, cols, 0_u32);
+        let mut total_pixels = DMatrix::from_element(rows, cols, 0_u32);
+
+        for y in 0..binary.nrows() {
+            for x in 0..binary.ncols() {
+                let block = (y / block_size, x / block_size);
+                total_pixels[block] += 1;
+                if binary[(y, x)] {
+                    black_pixels[block] += 1;
+                }
+            }
         }
+
+        DMatrix::from_fn(rows, cols, |row, col| {
+            black_pixels[(row, col)] as f32 > total_pixels[(row, col)] as f32 * Self::DARK_BLOCK_RATIO
+        })
     }
 
+    /// Floods from block at x,y to all connected dark blocks. The region is marked inverted if
+    /// it is large enough.
     fn check_block(&mut self, x: u32, y: u32) {
+        let visited = self.visited.as_mut().unwrap();
+        let dark = self.dark.as_ref().unwrap();
+
+        if visited[(y as usize, x as usize)] {
+            return;
+        }
+        visited[(y as usize, x as usize)] = true;
+
+        if !dark[(y as usize, x as usize)] {
+            return;
+        }
+
         let mut marked: Vec<Block> = vec![];
+        let mut todo = vec![Block { x, y }];
+
+        while let Some(block) = todo.pop() {
+            for neighbour in block.neighbours(self.width, self.height) {
+                let index = (neighbour.y as usize, neighbour.x as usize);
+                if visited[index] {
+                    continue;
+                }
+                visited[index] = true;
+
+                if dark[index] {
+                    todo.push(neighbour);
+                }
+            }
+            marked.push(block);
+        }
+
+        let black_blocks = marked.len();
+        if black_blocks < Self::MIN_INVERTED_BLOCKS {
+            return;
+        }
+
+        for block in &marked {
+            self.mark_inverted(block);
+        }
+    }
+
+    fn mark_inverted(&mut self, block: &Block) {
+        self.invert.as_mut().unwrap()[(block.y as usize, block.x as usize)] = true;
+
+        let neighbours_inverted = self.neighbours_inverted.as_mut().unwrap();
+        for neighbour in block.neighbours(self.width, self.height) {
+            neighbours_inverted[(neighbour.y as usize, neighbour.x as usize)] += 1;
+        }
+    }
+
+    fn invert_surrounded_blocks(&mut self) {
+        loop {
+            let mut surrounded: Vec<Block> = vec![];
+            {
+                let invert = self.invert.as_ref().unwrap();
+                let neighbours_inverted = self.neighbours_inverted.as_ref().unwrap();
+
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        let index = (y as usize, x as usize);
+                        if !invert[index] && neighbours_inverted[index] >= Self::MIN_INVERTED_NEIGHBOURS {
+                            surrounded.push(Block { x, y });
+                        }
+                    }
+                }
+            }
+
+            if surrounded.is_empty() {
+                break;
+            }
+
+            for block in &surrounded {
+                self.mark_inverted(block);
+            }
+        }
+    }
+
+    /// Inverts pixels inside inverted blocks. Region rarely ends at a block edge, so the
+    /// background around it turns black as well. Black pixels connected to the outer boundary
+    /// of the region are most likely this border and are marked as border pixels so that they
+    /// won't be used as areas. Text inside the region is kept even next to its edge, and
+    /// blocks outside the region are left alone, they can have normal text right next to it.
+    fn invert_pixels<P>(&self, task: &mut AreaTask<P>)
+    where
+        P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
+    {
+        let invert = self.invert.as_ref().unwrap();
+        let block_size = Self::BLOCK_SIZE as usize;
+
+        let binary = task.binary_image.as_mut().unwrap();
+        let inverted = DMatrix::from_fn(binary.nrows(), binary.ncols(), |y, x| {
+            invert[(y / block_size, x / block_size)]
+        });
+
+        for (pixel, &invert) in binary.iter_mut().zip(inverted.iter()) {
+            if invert {
+                *pixel = !*pixel;
+            }
+        }
+
+        task.border_pixels = Some(Self::find_border_pixels(binary, &inverted));
+        task.inverted = Some(inverted);
+    }
+
+    /// Floods from black inverted pixels next to pixels that weren't inverted to every
+    /// connected black inverted pixel.
+    fn find_border_pixels(binary: &DMatrix<bool>, inverted: &DMatrix<bool>) -> DMatrix<bool> {
+        let (rows, cols) = (binary.nrows() as i64, binary.ncols() as i64);
+        let inside = |x: i64, y: i64| x >= 0 && y >= 0 && x < cols && y < rows;
+        let mut border_pixels = DMatrix::from_element(binary.nrows(), binary.ncols(), false);
+        let mut todo = vec![];
+
+        for y in 0..rows {
+            for x in 0..cols {
+                let index = (y as usize, x as usize);
+                if !inverted[index] || !binary[index] {
+                    continue;
+                }
+
+                let outside = [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)].iter()
+                    .any(|&(nx, ny)| inside(nx, ny) && !inverted[(ny as usize, nx as usize)]);
+                if outside {
+                    border_pixels[index] = true;
+                    todo.push((x, y));
+                }
+            }
+        }
+
+        while let Some((x, y)) = todo.pop() {
+            for ny in (y - 1)..=(y + 1) {
+                for nx in (x - 1)..=(x + 1) {
+                    if !inside(nx, ny) {
+                        continue;
+                    }
+
+                    let index = (ny as usize, nx as usize);
+                    if inverted[index] && binary[index] && !border_pixels[index] {
+                        border_pixels[index] = true;
+                        todo.push((nx, ny));
+                    }
+                }
+            }
+        }
+
+        border_pixels
+    }
+}
+
+/// Finds groups of 8-connected black pixels from the binary image. Border pixels of
+/// inverted regions are ignored.
+struct AreaFinder<'a, P>
+where
+    P: Pixel<Subpixel = u8> + 'static
+{
+    image: &'a ImageBuffer<P, Vec<u8>>,
+    binary: &'a DMatrix<bool>,
+    inverted: Option<&'a DMatrix<bool>>,
+    border_pixels: Option<&'a DMatrix<bool>>,
+    visited: DMatrix<bool>,
+    todo: Vec<Point>,
+    pixels: Vec<Point>,
+}
+
+impl<'a, P> AreaFinder<'a, P>
+where
+    P: Pixel<Subpixel = u8> + 'static
+{
+    fn new(
+        image: &'a ImageBuffer<P, Vec<u8>>,
+        binary: &'a DMatrix<bool>,
+        inverted: Option<&'a DMatrix<bool>>,
+        border_pixels: Option<&'a DMatrix<bool>>
+    ) -> Self {
+        Self {
+            image,
+            binary,
+            inverted,
+            border_pixels,
+            visited: DMatrix::from_element(binary.nrows(), binary.ncols(), false),
+            todo: vec![],
+            pixels: vec![],
+        }
+    }
+
+    fn run(&mut self) -> Vec<Area> {
+        let mut areas = vec![];
+
+        for y in 0..self.binary.nrows() {
+            for x in 0..self.binary.ncols() {
+                if self.visited[(y, x)] || !self.is_black(x, y) {
+                    continue;
+                }
+
+                self.visited[(y, x)] = true;
+                self.todo.push(Point { x: x as u32, y: y as u32 });
+                while let Some(point) = self.todo.pop() {
+                    self.check_pixel(point);
+                }
+
+                areas.push(self.build_new_area());
+                self.pixels.clear();
+            }
+        }
+
+        areas
+    }
+
+    fn is_black(&self, x: usize, y: usize) -> bool {
+        let border = match self.border_pixels {
+            Some(border_pixels) => border_pixels[(y, x)],
+            None => false
+        };
+
+        self.binary[(y, x)] && !border
+    }
+
+    fn check_pixel(&mut self, point: Point) {
+        self.pixels.push(point);
+
+        let (x, y) = (point.x as i64, point.y as i64);
+        for ny in (y - 1)..=(y + 1) {
+            for nx in (x - 1)..=(x + 1) {
+                if nx < 0 || ny < 0 || nx >= self.binary.ncols() as i64 || ny >= self.binary.nrows() as i64 {
+                    continue;
+                }
+
+                let (nx, ny) = (nx as usize, ny as usize);
+                if self.visited[(ny, nx)] || !self.is_black(nx, ny) {
+                    continue;
+                }
 
-        let black_blocks = 0;
+                self.visited[(ny, nx)] = true;
+                self.todo.push(Point { x: nx as u32, y: ny as u32 });
+            }
+        }
+    }
+
+    fn build_new_area(&self) -> Area {
+        let mut min_x = u32::max_value();
+        let mut min_y = u32::max_value();
+        let mut max_x = 0;
+        let mut max_y = 0;
+        let mut min_rgb = 0x00ffffff_u32;
+        let mut min_sum = u32::max_value();
+
+        for px in &self.pixels {
+            if px.x < min_x { min_x = px.x };
+            if px.y < min_y { min_y = px.y };
+            if px.x > max_x { max_x = px.x };
+            if px.y > max_y { max_y = px.y };
+
+            let rgb = self.image.get_pixel(px.x, px.y).to_rgb();
+            let sum = rgb[0] as u32 + rgb[1] as u32 + rgb[2] as u32;
+            if sum < min_sum {
+                min_sum = sum;
+                min_rgb = ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | rgb[2] as u32;
+            }
+        }
+
+        let rect = Rect {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1
+        };
+
+        let mut mask = DMatrix::from_element(rect.height as usize, rect.width as usize, false);
+        for px in &self.pixels {
+            mask[((px.y - min_y) as usize, (px.x - min_x) as usize)] = true;
+        }
+
+        let inverted_pixels = match self.inverted {
+            Some(inverted) => self.pixels.iter().filter(|px| inverted[(px.y as usize, px.x as usize)]).count(),
+            None => 0
+        };
+
+        let mut area = Area::from_mask(rect, mask);
+        area.min_rgb = min_rgb;
+        area.inverted = inverted_pixels * 2 > self.pixels.len();
+        area
+    }
+}
+
+/// Merges copies and fragments of the same area found in overlapping tiles. Areas that
+/// share black pixels are parts of the same connected component. Shared pixels are always
+/// inside one of the overlap `strips`, so only areas that reach the same strip are compared,
+/// sorted along the strip so that the comparison stops at the first area past the current one.
+fn merge_tile_areas(areas: Vec<Area>, strips: &[Rect]) -> Vec<Area> {
+    let mut regions: Vec<usize> = (0..areas.len()).collect();
+    for strip in strips {
+        let vertical = strip.height > strip.width;
+        let mut inside: Vec<usize> = (0..areas.len())
+            .filter(|&i| intersects(&areas[i].get_rectangle(), strip))
+            .collect();
+        inside.sort_by_key(|&i| along(&areas[i].get_rectangle(), vertical).0);
+
+        for (n, &i) in inside.iter().enumerate() {
+            let end = along(&areas[i].get_rectangle(), vertical).1;
+            for &j in &inside[n + 1..] {
+                if along(&areas[j].get_rectangle(), vertical).0 >= end {
+                    break;
+                }
+                if areas[i].shares_pixels(&areas[j]) {
+                    union_regions(&mut regions, i, j);
+                }
+            }
+        }
+    }
 
-        
+    let mut merged: Vec<Option<Area>> = vec![None; areas.len()];
+    for (i, area) in areas.into_iter().enumerate() {
+        let region = find_region(&mut regions, i);
+        match merged[region] {
+            Some(ref mut target) => target.add_pixels(&area),
+            None => merged[region] = Some(area)
+        }
     }
+
+    merged.into_iter().flatten().collect()
 }
 
 struct Block {
     x: u32,
     y: u32,
 }
+
+impl Block {
+    fn neighbours(&self, width: u32, height: u32) -> Vec<Block> {
+        let mut neighbours = Vec::with_capacity(4);
+
+        if self.x > 0 {
+            neighbours.push(Block { x: self.x - 1, y: self.y });
+        }
+        if self.x + 1 < width {
+            neighbours.push(Block { x: self.x + 1, y: self.y });
+        }
+        if self.y > 0 {
+            neighbours.push(Block { x: self.x, y: self.y - 1 });
+        }
+        if self.y + 1 < height {
+            neighbours.push(Block { x: self.x, y: self.y + 1 });
+        }
+
+        neighbours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+    use crate::preprocess::{Scale, Threshold};
+    use crate::ScaleFilter;
+
+    #[test]
+    fn test_detect_white_on_black() {
+        let mut task = AreaTask::new(RgbaImage::new(60, 60), Arc::new(Parameters::default()));
+        task.binary_image = Some(DMatrix::from_fn(60, 60, |y, x| {
+            let left_character = x >= 5 && x < 10 && y >= 20 && y < 25;
+            let right_character = x >= 50 && x < 55 && y >= 20 && y < 25;
+            let next_to_region = x >= 32 && x < 37 && y >= 40 && y < 45;
+            if x < 30 {
+                !left_character
+            } else {
+                right_character || next_to_region
+            }
+        }));
+
+        InvertImageData::new().detect_b_on_w(&mut task);
+
+        let binary = task.binary_image.as_ref().unwrap();
+        assert!(binary[(22, 7)]);
+        assert!(!binary[(2, 2)]);
+        assert!(binary[(22, 52)]);
+
+        let inverted = task.inverted.as_ref().unwrap();
+        assert!(inverted[(0, 0)]);
+        assert!(!inverted[(0, 59)]);
+
+        let border_pixels = task.border_pixels.as_ref().unwrap();
+        assert!(!border_pixels[(22, 7)]);
+        // Text in the block right outside the inverted region isn't part of its border
+        assert!(binary[(42, 34)]);
+        assert!(!border_pixels[(42, 34)]);
+    }
+
+    #[test]
+    fn test_border_pixels() {
+        let mut task = AreaTask::new(RgbaImage::new(60, 60), Arc::new(Parameters::default()));
+        // Dark region ends three pixels before the block edge, character is in the edge block
+        task.binary_image = Some(DMatrix::from_fn(60, 60, |y, x| {
+            let character = x >= 19 && x < 23 && y >= 20 && y < 24;
+            x < 27 && !character
+        }));
+
+        InvertImageData::new().detect_b_on_w(&mut task);
+
+        let binary = task.binary_image.as_ref().unwrap();
+        let border_pixels = task.border_pixels.as_ref().unwrap();
+        // Background next to the region turns black when its block is inverted
+        assert!(binary[(10, 28)]);
+        assert!(border_pixels[(10, 28)]);
+        assert!(binary[(21, 20)]);
+        assert!(!border_pixels[(21, 20)]);
+
+        task.find_areas();
+        let areas = task.areas.as_ref().unwrap();
+        assert_eq!(1, areas.len());
+        assert_eq!(Rect { x: 19, y: 20, width: 4, height: 4 }, areas[0].get_rectangle());
+        assert!(areas[0].inverted);
+    }
+
+    #[test]
+    fn test_find_areas_tiled() {
+        let image = RgbaImage::from_fn(100, 60, |x, y| {
+            let bar = x >= 5 && x < 95 && y >= 10 && y < 14;
+            let square = x >= 40 && x < 46 && y >= 30 && y < 36;
+            let dot = x >= 20 && x < 24 && y >= 45 && y < 49;
+            if bar || square || dot {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let parameters = Arc::new(Parameters::default());
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Threshold::new(parameters.clone()));
+        let mut detector = AreaDetector::new(AreaTask::new(image, parameters), Arc::new(pipeline));
+
+        assert!(!detector.find_areas_tiled(32, 8));
+
+        let mut areas = detector.task.areas.unwrap();
+        areas.sort_by_key(|area| (area.get_y(), area.get_x()));
+        let rects: Vec<Rect> = areas.iter().map(|area| area.get_rectangle()).collect();
+        assert_eq!(vec![
+            Rect { x: 5, y: 10, width: 90, height: 4 },
+            Rect { x: 40, y: 30, width: 6, height: 6 },
+            Rect { x: 20, y: 45, width: 4, height: 4 },
+        ], rects);
+        assert_eq!(360, areas[0].get_pixels());
+        assert_eq!(36, areas[1].get_pixels());
+    }
+
+    #[test]
+    fn test_find_areas_tiled_scaled() {
+        let image = RgbaImage::from_fn(100, 60, |x, y| {
+            if x >= 40 && x < 46 && y >= 30 && y < 36 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let parameters = Arc::new(Parameters::default());
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Threshold::new(parameters.clone()));
+        pipeline.push(Scale { factor: 2, filter: ScaleFilter::Nearest });
+        let mut detector = AreaDetector::new(AreaTask::new(image, parameters), Arc::new(pipeline));
+
+        assert!(!detector.find_areas_tiled(32, 8));
+
+        let areas = detector.task.areas.as_ref().unwrap();
+        assert_eq!(1, areas.len());
+        assert_eq!(Rect { x: 80, y: 60, width: 12, height: 12 }, areas[0].get_rectangle());
+        assert_eq!(2, detector.task.scale_factor);
+        assert_eq!((200, 120), detector.task.original_image.dimensions());
+        assert_eq!(
+            Rect { x: 40, y: 30, width: 6, height: 6 },
+            detector.task.map_to_original(areas[0].get_rectangle())
+        );
+    }
+
+    #[test]
+    fn test_find_areas_tiled_deskew_and_upscale() {
+        let slope = 3.0_f32.to_radians().tan();
+        let image = RgbaImage::from_fn(100, 60, |x, y| {
+            let offset = (y as f32 - 30.0) - slope * (x as f32 - 50.0);
+            if (-2..=2).any(|line| (offset - line as f32 * 10.0).abs() < 1.0) {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let parameters = Arc::new(Parameters { deskew: true, ..Default::default() });
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Threshold::new(parameters.clone()));
+        let pipeline = Arc::new(pipeline);
+        let mut detector = AreaDetector::new(AreaTask::new(image, parameters), pipeline.clone());
+
+        assert!(detector.find_areas_tiled(32, 8));
+        assert!((detector.task.skew_angle.unwrap() - 3.0).abs() < 0.5);
+        assert!(!detector.find_areas_tiled(32, 8));
+
+        let image = RgbaImage::from_fn(100, 60, |x, y| {
+            if y >= 10 && y < 16 && (x >= 10 && x < 16 || x >= 70 && x < 76) {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let parameters = Arc::new(Parameters { upscale: true, upscale_filter: ScaleFilter::Nearest, ..Default::default() });
+        let mut detector = AreaDetector::new(AreaTask::new(image, parameters), pipeline);
+
+        assert!(detector.find_areas_tiled(32, 8));
+        assert_eq!(4, detector.task.scale_factor);
+        assert!(!detector.find_areas_tiled(32, 8));
+
+        let mut areas = detector.task.areas.unwrap();
+        areas.sort_by_key(|area| area.get_x());
+        assert_eq!(Rect { x: 40, y: 40, width: 24, height: 24 }, areas[0].get_rectangle());
+    }
+
+    #[test]
+    fn test_upscale() {
+        let mut task = AreaTask::new(RgbaImage::new(40, 20), Arc::new(Parameters::default()));
+        task.binary_image = Some(DMatrix::from_fn(20, 40, |y, x| {
+            y >= 5 && y < 11 && (x >= 5 && x < 11 || x >= 20 && x < 26)
+        }));
+
+        task.find_areas();
+
+        assert!(task.upscale());
+        assert_eq!(4, task.scale_factor);
+        assert_eq!((160, 80), task.original_image.dimensions());
+        assert_eq!(Point { x: 40, y: 32 }, task.map_point(Point { x: 10, y: 8 }));
+        assert_eq!(
+            Rect { x: 5, y: 5, width: 6, height: 6 },
+            task.map_to_original(Rect { x: 20, y: 20, width: 24, height: 24 })
+        );
+    }
+
+    #[test]
+    fn test_get_areas() {
+        let mut task = AreaTask::new(RgbaImage::new(130, 90), Arc::new(Parameters::default()));
+        task.binary_image = Some(DMatrix::from_fn(90, 130, |y, x| {
+            let right = x >= 100 && x < 120 && [10, 34, 58].iter().any(|&start| y >= start && y < start + 20);
+            let left = x >= 70 && x < 90 && [10, 34].iter().any(|&start| y >= start && y < start + 20);
+            right || left
+        }));
+
+        task.find_areas();
+        task.find_columns();
+        task.find_furigana();
+        task.find_punctuation();
+        task.merge_areas();
+        task.split_areas();
+        task.link_columns();
+
+        let areas = task.get_areas(Point { x: 110, y: 36 });
+        assert_eq!(4, areas.len());
+        assert_eq!(34, areas[0].get_y());
+        assert_eq!(70, areas[2].get_x());
+        assert_eq!(4, task.get_sub_images(Point { x: 110, y: 36 }).len());
+        assert!(task.get_area(Point { x: 10, y: 80 }).is_none());
+    }
+
+    #[test]
+    fn test_find_areas() {
+        let mut task = AreaTask::new(RgbaImage::new(20, 10), Arc::new(Parameters::default()));
+        task.binary_image = Some(DMatrix::from_fn(10, 20, |y, x| {
+            let diagonal = x == y && x < 4;
+            let square = x >= 10 && x < 13 && y >= 5 && y < 8;
+            diagonal || square
+        }));
+
+        task.find_areas();
+
+        let areas = task.areas.as_ref().unwrap();
+        assert_eq!(2, areas.len());
+        assert_eq!(Rect { x: 0, y: 0, width: 4, height: 4 }, areas[0].get_rectangle());
+        assert_eq!(4, areas[0].get_pixels());
+        assert_eq!(Rect { x: 10, y: 5, width: 3, height: 3 }, areas[1].get_rectangle());
+        assert_eq!(9, areas[1].get_pixels());
+    }
+}
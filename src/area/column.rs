@@ -1,7 +1,14 @@
-use crate::area::Area;
+use crate::area::{Area, Point};
+use crate::area::punctuation;
+use crate::traits::HasRectangle;
+use crate::Orientation;
+use crate::parameters::Parameters;
 use image::math::Rect;
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::cmp::Reverse;
+use nalgebra::DMatrix;
 
 #[derive(Debug, Clone)]
 pub(crate) struct Column {
@@ -17,11 +24,993 @@ pub(crate) struct Column {
     remove: bool,
 }
 
+impl HasRectangle for Column {
+    fn get_rectangle(&self) -> Rect {
+        self.rect
+    }
+
+    #[inline(always)]
+    fn get_midpoint(&self) -> Point {
+        Point {
+            x: self.rect.x + self.rect.width / 2,
+            y: self.rect.y + self.rect.height / 2,
+        }
+    }
+}
+
 impl Column {
+    /// Minimum overlap perpendicular to reading direction between an area and a column,
+    /// relative to the thinner one.
+    const MIN_OVERLAP: f32 = 0.5;
+    /// Maximum gap between consecutive areas relative to column thickness.
+    const MAX_AREA_GAP: f32 = 1.0;
+    /// Maximum gap between a column and the next column relative to column thickness.
+    const MAX_COLUMN_GAP: f32 = 2.0;
+    /// Maximum thickness of a furigana column relative to the main column.
+    const MAX_FURIGANA_THICKNESS: f32 = 0.6;
+    /// Maximum gap between furigana column and main column relative to main column thickness.
+    const MAX_FURIGANA_GAP: f32 = 0.5;
+    /// Minimum share of furigana column length that must be next to the main column.
+    const MIN_FURIGANA_OVERLAP: f32 = 0.8;
+    /// Areas smaller than this share of column thickness aren't used to estimate character pitch.
+    const MIN_PITCH_AREA_SIZE: f32 = 0.5;
+    /// Maximum size of merged area relative to character pitch.
+    const MAX_MERGED_SIZE: f32 = 1.1;
+    /// Parts of a character that follow each other in reading direction are flat strokes, like
+    /// the bars of 二. Parts longer than this relative to character pitch are characters of
+    /// their own, like small kana.
+    const MAX_FLAT_PART_LENGTH: f32 = 0.35;
+    /// Areas at least this long relative to character pitch are split.
+    const MIN_SPLIT_SIZE: f32 = 1.6;
+    /// Split point is searched this far from the expected position relative to character pitch.
+    const SPLIT_SEARCH_RANGE: f32 = 0.25;
+    /// Maximum gap between areas of the same text region relative to the smaller area.
+    const MAX_REGION_GAP: f32 = 0.5;
+
+    pub(crate) fn new(areas: Vec<Area>, vertical: bool) -> Self {
+        let rect = areas.iter()
+            .map(|a| a.get_rectangle())
+            .fold(None, |acc: Option<Rect>, r| Some(acc.map_or(r, |acc| union(&acc, &r))))
+            .unwrap_or(Rect { x: 0, y: 0, width: 0, height: 0 });
+
+        let mut column = Self {
+            areas,
+            rect,
+            vertical,
+            furigana: false,
+            furigana_columns: vec![],
+            area_distance: 0.0,
+            score: 0.0,
+            next_column: None,
+            previous_column: None,
+            remove: false,
+        };
+        column.area_distance = column.calculate_area_distance();
+        column.score = column.calculate_score();
+
+        column
+    }
+
+    pub(crate) fn is_vertical(&self) -> bool {
+        self.vertical
+    }
+
+    pub(crate) fn get_orientation(&self) -> Orientation {
+        if self.vertical {
+            Orientation::Vertical
+        } else {
+            Orientation::Horizontal
+        }
+    }
+
+    pub(crate) fn get_score(&self) -> f32 {
+        self.score
+    }
+
+    pub(crate) fn is_furigana(&self) -> bool {
+        self.furigana
+    }
+
+    pub(crate) fn get_furigana_columns(&self) -> &[Column] {
+        &self.furigana_columns
+    }
+
+    pub(crate) fn get_next_column(&self) -> Option<Rc<RefCell<Column>>> {
+        self.next_column.clone()
+    }
+
+    pub(crate) fn get_previous_column(&self) -> Option<Rc<RefCell<Column>>> {
+        self.previous_column.as_ref().and_then(Weak::upgrade)
+    }
+
+    /// Size of the column perpendicular to reading direction.
+    pub(crate) fn thickness(&self) -> u32 {
+        let (start, end) = across(&self.rect, self.vertical);
+        end - start
+    }
+
+    /// Average gap between consecutive areas relative to column thickness.
+    fn calculate_area_distance(&self) -> f32 {
+        if self.areas.len() < 2 || self.thickness() == 0 {
+            return 0.0;
+        }
+
+        let gaps: u32 = self.areas.windows(2)
+            .map(|pair| {
+                let (_, previous_end) = along(&pair[0].get_rectangle(), self.vertical);
+                let (start, _) = along(&pair[1].get_rectangle(), self.vertical);
+                start.saturating_sub(previous_end)
+            })
+            .sum();
+
+        gaps as f32 / (self.areas.len() - 1) as f32 / self.thickness() as f32
+    }
+
+    /// Areas that fill the column perpendicular to reading direction and follow each other
+    /// closely are evidence that the column has the right orientation. Single area
+    /// doesn't tell anything and scores zero.
+    fn calculate_score(&self) -> f32 {
+        let thickness = self.thickness() as f32;
+        if thickness == 0.0 {
+            return 0.0;
+        }
+
+        let alignment: f32 = self.areas.iter()
+            .map(|a| {
+                let (start, end) = across(&a.get_rectangle(), self.vertical);
+                (end - start) as f32 / thickness
+            })
+            .sum();
+
+        (alignment - 1.0).max(0.0) / (1.0 + 2.0 * self.area_distance)
+    }
+
+    /// Groups areas into vertical and horizontal columns. Areas are split into regions
+    /// separated by empty space, such as text in different speech bubbles, and orientation
+    /// of each region is decided by `detect_orientation`. Candidate columns that reach over
+    /// to another region are cut at the region border.
+    ///
+    /// Returns (vertical columns, horizontal columns).
+    pub(crate) fn find_columns(areas: &[Area], parameters: &Parameters) -> (Vec<Column>, Vec<Column>) {
+        let regions = Self::find_regions(areas);
+        let vertical_columns = Self::region_columns(areas, &regions, true);
+        let horizontal_columns = Self::region_columns(areas, &regions, false);
+
+        let mut vertical_scores: HashMap<usize, f32> = HashMap::new();
+        for (region, column) in &vertical_columns {
+            *vertical_scores.entry(*region).or_insert(0.0) += column.score;
+        }
+
+        let mut horizontal_scores: HashMap<usize, f32> = HashMap::new();
+        for (region, column) in &horizontal_columns {
+            *horizontal_scores.entry(*region).or_insert(0.0) += column.score;
+        }
+
+        let is_vertical = |region: usize| {
+            let orientation = detect_orientation(
+                vertical_scores.get(&region).copied().unwrap_or(0.0),
+                horizontal_scores.get(&region).copied().unwrap_or(0.0),
+                parameters.orientation_target,
+                parameters.vertical
+            );
+            orientation == Orientation::Vertical
+        };
+
+        let vertical = vertical_columns.into_iter()
+            .filter(|(region, _)| is_vertical(*region))
+            .map(|(_, column)| column)
+            .collect();
+        let horizontal = horizontal_columns.into_iter()
+            .filter(|(region, _)| !is_vertical(*region))
+            .map(|(_, column)| column)
+            .collect();
+
+        (vertical, horizontal)
+    }
+
+    /// Areas closer to each other than `MAX_REGION_GAP` times the size of the smaller area
+    /// belong to the same region. Returns region of every area.
+    ///
+    /// Areas are swept from left to right, each area is only compared against areas that
+    /// start before its right edge plus the largest gap it can have to a region neighbour.
+    fn find_regions(areas: &[Area]) -> Vec<usize> {
+        let mut regions: Vec<usize> = (0..areas.len()).collect();
+
+        let mut order: Vec<usize> = (0..areas.len()).collect();
+        order.sort_by_key(|&i| areas[i].get_x());
+
+        for (n, &i) in order.iter().enumerate() {
+            let reach = (areas[i].get_x() + areas[i].width()) as f32 + areas[i].get_max_dim() as f32 * Self::MAX_REGION_GAP;
+
+            for &j in &order[n + 1..] {
+                if areas[j].get_x() as f32 > reach {
+                    break;
+                }
+
+                let max_gap = areas[i].get_max_dim().min(areas[j].get_max_dim()) as f32 * Self::MAX_REGION_GAP;
+                if gap(&areas[i].get_rectangle(), &areas[j].get_rectangle()) as f32 <= max_gap {
+                    union_regions(&mut regions, i, j);
+                }
+            }
+        }
+
+        (0..areas.len()).map(|i| find_region(&mut regions, i)).collect()
+    }
+
+    /// Candidate columns of each region in one orientation, as (region, column) pairs.
+    fn region_columns(areas: &[Area], regions: &[usize], vertical: bool) -> Vec<(usize, Column)> {
+        let mut columns = vec![];
+
+        for group in Self::group_areas(areas, vertical) {
+            let mut parts: Vec<(usize, Vec<Area>)> = vec![];
+            for i in group {
+                match parts.iter_mut().find(|(region, _)| *region == regions[i]) {
+                    Some((_, part)) => part.push(areas[i].clone()),
+                    None => parts.push((regions[i], vec![areas[i].clone()]))
+                }
+            }
+
+            columns.extend(parts.into_iter().map(|(region, part)| (region, Column::new(part, vertical))));
+        }
+
+        columns
+    }
+
+    /// Expected character size in reading direction. This is the median size of full sized
+    /// areas in the column, limited by column thickness since characters are roughly square.
+    pub(crate) fn character_pitch(&self) -> f32 {
+        let thickness = self.thickness() as f32;
+        let mut sizes: Vec<u32> = self.areas.iter()
+            .map(|a| a.get_max_dim())
+            .filter(|&size| size as f32 >= thickness * Self::MIN_PITCH_AREA_SIZE)
+            .collect();
+
+        if sizes.is_empty() {
+            return thickness;
+        }
+
+        sizes.sort();
+        (sizes[sizes.len() / 2] as f32).min(thickness)
+    }
+
+    /// Merges consecutive areas that together fit inside a single character. These are
+    /// characters with separated strokes, such as 川, 小 or 二. Punctuation must be found
+    /// before, punctuation areas are never merged.
+    pub(crate) fn merge_areas(&mut self) {
+        let pitch = self.character_pitch();
+        let vertical = self.vertical;
+        let max_size = pitch * Self::MAX_MERGED_SIZE;
+        let is_flat = |rect: &Rect| {
+            let (start, end) = along(rect, vertical);
+            (end - start) as f32 <= pitch * Self::MAX_FLAT_PART_LENGTH
+        };
+
+        let areas = std::mem::replace(&mut self.areas, vec![]);
+        let mut merged: Vec<Area> = Vec::with_capacity(areas.len());
+
+        for area in areas {
+            if let Some(last) = merged.last_mut().filter(|last| !last.punctuation && !area.punctuation) {
+                let (last_rect, rect) = (last.get_rectangle(), area.get_rectangle());
+                let combined = union(&last_rect, &rect);
+                let (along_start, along_end) = along(&combined, vertical);
+                let (across_start, across_end) = across(&combined, vertical);
+                let side_by_side = along(&rect, vertical).0 < along(&last_rect, vertical).1;
+                let parts = side_by_side || is_flat(&last_rect) || is_flat(&rect);
+
+                if parts && (along_end - along_start) as f32 <= max_size && (across_end - across_start) as f32 <= max_size {
+                    last.merge(area);
+                    continue;
+                }
+            }
+
+            merged.push(area);
+        }
+
+        self.areas = merged;
+    }
+
+    /// Splits areas that are much longer than character pitch. These are touching
+    /// characters that were detected as a single area.
+    pub(crate) fn split_areas(&mut self) {
+        let pitch = self.character_pitch();
+        let vertical = self.vertical;
+
+        let areas = std::mem::replace(&mut self.areas, vec![]);
+        let mut splitted = Vec::with_capacity(areas.len());
+
+        for area in areas {
+            let (along_start, along_end) = along(&area.get_rectangle(), vertical);
+            let length = (along_end - along_start) as f32;
+            if pitch == 0.0 || area.punctuation || length < pitch * Self::MIN_SPLIT_SIZE {
+                splitted.push(area);
+                continue;
+            }
+
+            let count = (length / pitch).round().max(2.0) as u32;
+            let cuts = Self::find_cuts(&area, count, pitch, vertical);
+            let pieces = area.split(&cuts, vertical);
+            if pieces.len() > 1 {
+                splitted.extend(pieces);
+            } else {
+                splitted.push(area);
+            }
+        }
+
+        self.areas = splitted;
+    }
+
+    /// Finds `count - 1` split positions in reading direction. Each split is placed where the
+    /// fewest black pixels cross the area near the expected character boundary.
+    fn find_cuts(area: &Area, count: u32, pitch: f32, vertical: bool) -> Vec<u32> {
+        let rect = area.get_rectangle();
+        let (along_start, along_end) = along(&rect, vertical);
+        let (across_start, across_end) = across(&rect, vertical);
+        let length = along_end - along_start;
+        let search_range = (pitch * Self::SPLIT_SEARCH_RANGE).round() as u32;
+
+        let projection: Vec<u32> = (along_start..along_end)
+            .map(|a| {
+                (across_start..across_end)
+                    .filter(|&c| {
+                        let (x, y) = if vertical { (c, a) } else { (a, c) };
+                        area.is_black(x, y)
+                    })
+                    .count() as u32
+            })
+            .collect();
+
+        let mut cuts = vec![];
+        for i in 1..count {
+            let expected = along_start + length * i / count;
+            let start = expected.saturating_sub(search_range).max(along_start + 1);
+            let end = (expected + search_range).min(along_end - 1);
+
+            let cut = (start..=end).min_by_key(|&a| {
+                let distance = if a > expected { a - expected } else { expected - a };
+                (projection[(a - along_start) as usize], distance)
+            });
+
+            if let Some(cut) = cut {
+                cuts.push(cut);
+            }
+        }
+
+        cuts
+    }
+
+    /// Marks punctuation areas of the column. Marks that were split into several areas
+    /// (…！？) are merged into one area. Areas that are already punctuation are kept, so
+    /// this can be run again on merged and split areas.
+    pub(crate) fn find_punctuation(&mut self) {
+        let thickness = self.thickness();
+        let rect = self.rect;
+        let vertical = self.vertical;
+
+        let characters: Vec<Option<char>> = (0..self.areas.len())
+            .map(|i| {
+                if self.areas[i].punctuation {
+                    None
+                } else {
+                    punctuation::classify(&self.areas, i, &rect, thickness, vertical)
+                }
+            })
+            .collect();
+
+        for (area, character) in self.areas.iter_mut().zip(characters) {
+            if let Some(character) = character {
+                area.set_punctuation(character);
+            }
+        }
+
+        self.merge_marks();
+    }
+
+    fn merge_marks(&mut self) {
+        let thickness = self.thickness() as f32;
+        let vertical = self.vertical;
+        let max_gap = thickness * punctuation::MAX_MARK_GAP;
+        let gap = |a: &Area, b: &Area| {
+            along(&b.get_rectangle(), vertical).0.saturating_sub(along(&a.get_rectangle(), vertical).1) as f32
+        };
+        let is_dot = |a: &Area| a.get_punctuation() == Some('・');
+
+        let areas = std::mem::replace(&mut self.areas, vec![]);
+        let mut merged: Vec<Area> = Vec::with_capacity(areas.len());
+        let mut i = 0;
+
+        while i < areas.len() {
+            if i + 2 < areas.len()
+                && is_dot(&areas[i]) && is_dot(&areas[i + 1]) && is_dot(&areas[i + 2])
+                && gap(&areas[i], &areas[i + 1]) <= max_gap && gap(&areas[i + 1], &areas[i + 2]) <= max_gap
+            {
+                let mut ellipsis = areas[i].clone();
+                ellipsis.merge(areas[i + 1].clone());
+                ellipsis.merge(areas[i + 2].clone());
+                ellipsis.set_punctuation('…');
+                merged.push(ellipsis);
+                i += 3;
+                continue;
+            }
+
+            if i + 1 < areas.len() && is_dot(&areas[i + 1]) && gap(&areas[i], &areas[i + 1]) <= max_gap {
+                let stroke = &areas[i];
+                let (stroke_start, stroke_end) = across(&stroke.get_rectangle(), vertical);
+                let (dot_start, dot_end) = across(&areas[i + 1].get_rectangle(), vertical);
+                let dot_center = (dot_start + dot_end) / 2;
+                let width = (stroke_end - stroke_start) as f32;
+                let can_end_with_dot = match stroke.get_punctuation() {
+                    None => true,
+                    Some(c) => c == '！' || c == '？'
+                };
+
+                if can_end_with_dot && dot_center >= stroke_start && dot_center < stroke_end && width <= thickness * punctuation::MAX_QUESTION_WIDTH {
+                    let mut mark = stroke.clone();
+                    mark.merge(areas[i + 1].clone());
+                    mark.set_punctuation(if width <= thickness * punctuation::MAX_THIN_WIDTH { '！' } else { '？' });
+                    merged.push(mark);
+                    i += 2;
+                    continue;
+                }
+            }
+
+            merged.push(areas[i].clone());
+            i += 1;
+        }
+
+        self.areas = merged;
+    }
+
+    /// Moves thin ruby columns next to main text into `furigana_columns` of the main column.
+    /// Vertical furigana is to the right of the main column, horizontal furigana above it.
+    ///
+    /// Roles are decided from the thickest column down, since furigana is always thinner than
+    /// its main column, and columns are attached only after every role is known.
+    ///
+    /// Returns the remaining main columns.
+    pub(crate) fn find_furigana(columns: Vec<Rc<RefCell<Column>>>) -> Vec<Rc<RefCell<Column>>> {
+        let mut order: Vec<usize> = (0..columns.len()).collect();
+        order.sort_by_key(|&i| Reverse(columns[i].borrow().thickness()));
+
+        let mut mains = vec![None; columns.len()];
+        for i in order {
+            mains[i] = Self::find_main_column(&columns, i);
+            if mains[i].is_some() {
+                let mut furigana = columns[i].borrow_mut();
+                furigana.furigana = true;
+                furigana.remove = true;
+            }
+        }
+
+        for (i, main) in mains.into_iter().enumerate() {
+            if let Some(main) = main {
+                let furigana = columns[i].borrow().clone();
+                columns[main].borrow_mut().furigana_columns.push(furigana);
+            }
+        }
+
+        columns.into_iter().filter(|c| !c.borrow().remove).collect()
+    }
+
+    fn find_main_column(columns: &[Rc<RefCell<Column>>], index: usize) -> Option<usize> {
+        let candidate = columns[index].borrow();
+        let rect = candidate.rect;
+        let (along_start, along_end) = along(&rect, candidate.vertical);
+
+        let mut best: Option<(usize, u32)> = None;
+        for (i, main) in columns.iter().enumerate() {
+            if i == index {
+                continue;
+            }
+
+            let main = main.borrow();
+            if main.vertical != candidate.vertical || main.furigana {
+                continue;
+            }
+
+            let main_thickness = main.thickness() as f32;
+            if candidate.thickness() as f32 > main_thickness * Self::MAX_FURIGANA_THICKNESS {
+                continue;
+            }
+
+            let max_area_size = candidate.areas.iter().map(|a| a.get_max_dim()).max().unwrap_or(0);
+            if max_area_size as f32 > main_thickness * Self::MAX_FURIGANA_THICKNESS {
+                continue;
+            }
+
+            let (main_start, main_end) = along(&main.rect, main.vertical);
+            let overlap = along_end.min(main_end).saturating_sub(along_start.max(main_start));
+            if (overlap as f32) < (along_end - along_start) as f32 * Self::MIN_FURIGANA_OVERLAP {
+                continue;
+            }
+
+            let main_rect = main.rect;
+            let tolerance = main.thickness() / 4;
+            let gap = if candidate.vertical {
+                if rect.x + tolerance < main_rect.x + main_rect.width {
+                    continue;
+                }
+                rect.x.saturating_sub(main_rect.x + main_rect.width)
+            } else {
+                if rect.y + rect.height > main_rect.y + tolerance {
+                    continue;
+                }
+                main_rect.y.saturating_sub(rect.y + rect.height)
+            };
+
+            if gap as f32 > main_thickness * Self::MAX_FURIGANA_GAP {
+                continue;
+            }
+
+            if best.map_or(true, |(_, best_gap)| gap < best_gap) {
+                best = Some((i, gap));
+            }
+        }
+
+        best.map(|(i, _)| i)
+    }
+
+    /// Groups areas into candidate columns. Each area joins the closest column that it
+    /// overlaps perpendicular to reading direction, or starts a new one.
+    fn group_areas(areas: &[Area], vertical: bool) -> Vec<Vec<usize>> {
+        let mut order: Vec<usize> = (0..areas.len()).collect();
+        order.sort_by_key(|&i| along(&areas[i].get_rectangle(), vertical).0);
+
+        let mut groups: Vec<Vec<usize>> = vec![];
+        let mut bounds: Vec<Rect> = vec![];
+
+        for i in order {
+            let rect = areas[i].get_rectangle();
+            let (start, _) = along(&rect, vertical);
+            let (area_start, area_end) = across(&rect, vertical);
 
+            let mut best: Option<(usize, u32)> = None;
+            for (group, bound) in bounds.iter().enumerate() {
+                let (bound_start, bound_end) = across(bound, vertical);
+                let overlap = bound_end.min(area_end).saturating_sub(bound_start.max(area_start));
+                let min_thickness = (bound_end - bound_start).min(area_end - area_start);
+                if (overlap as f32) < min_thickness as f32 * Self::MIN_OVERLAP {
+                    continue;
+                }
+
+                let last = areas[*groups[group].last().unwrap()].get_rectangle();
+                let gap = start.saturating_sub(along(&last, vertical).1);
+                let max_thickness = (bound_end - bound_start).max(area_end - area_start);
+                if gap as f32 > max_thickness as f32 * Self::MAX_AREA_GAP {
+                    continue;
+                }
+
+                if best.map_or(true, |(_, best_gap)| gap < best_gap) {
+                    best = Some((group, gap));
+                }
+            }
+
+            match best {
+                Some((group, _)) => {
+                    groups[group].push(i);
+                    bounds[group] = union(&bounds[group], &rect);
+                },
+                None => {
+                    groups.push(vec![i]);
+                    bounds.push(rect);
+                }
+            }
+        }
+
+        groups
+    }
+
+    /// Finds the column that continues the text after column at `index`. Vertical text
+    /// continues to the left, horizontal text continues below.
+    fn find_next_column(columns: &[Rc<RefCell<Column>>], index: usize, linked: &[bool]) -> Option<usize> {
+        let column = columns[index].borrow();
+        let rect = column.rect;
+        let max_gap = column.thickness() as f32 * Self::MAX_COLUMN_GAP;
+        let tolerance = column.thickness() / 4;
+
+        let mut best: Option<(usize, u32)> = None;
+        for (i, other) in columns.iter().enumerate() {
+            if i == index || linked[i] {
+                continue;
+            }
+
+            let other = other.borrow();
+            if other.vertical != column.vertical {
+                continue;
+            }
+
+            let other_rect = other.rect;
+            let (gap, overlap) = if column.vertical {
+                if other_rect.x + other_rect.width > rect.x + tolerance {
+                    continue;
+                }
+                let overlap = (rect.y + rect.height).min(other_rect.y + other_rect.height).saturating_sub(rect.y.max(other_rect.y));
+                (rect.x.saturating_sub(other_rect.x + other_rect.width), overlap)
+            } else {
+                if other_rect.y + tolerance < rect.y + rect.height {
+                    continue;
+                }
+                let overlap = (rect.x + rect.width).min(other_rect.x + other_rect.width).saturating_sub(rect.x.max(other_rect.x));
+                (other_rect.y.saturating_sub(rect.y + rect.height), overlap)
+            };
+
+            if overlap == 0 || gap as f32 > max_gap {
+                continue;
+            }
+
+            if best.map_or(true, |(_, best_gap)| gap < best_gap) {
+                best = Some((i, gap));
+            }
+        }
+
+        best.map(|(i, _)| i)
+    }
 }
 
+/// Chain of columns in reading order.
+#[derive(Debug, Clone, Default)]
 pub(crate) struct ColumnList {
     first: Option<Rc<RefCell<Column>>>,
     last: Option<Rc<RefCell<Column>>>,
-}
\ No newline at end of file
+}
+
+impl ColumnList {
+    pub(crate) fn new() -> Self {
+        Self {
+            first: None,
+            last: None,
+        }
+    }
+
+    /// Links columns into reading order chains. Columns with no suitable column before them
+    /// start a new chain.
+    pub(crate) fn build(columns: &[Rc<RefCell<Column>>]) -> Vec<ColumnList> {
+        let mut order: Vec<usize> = (0..columns.len()).collect();
+        order.sort_by_key(|&i| {
+            let column = columns[i].borrow();
+            if column.vertical {
+                -((column.rect.x + column.rect.width) as i64)
+            } else {
+                column.rect.y as i64
+            }
+        });
+
+        let mut linked = vec![false; columns.len()];
+        let mut lists = vec![];
+
+        for i in order {
+            if linked[i] {
+                continue;
+            }
+
+            let mut list = ColumnList::new();
+            list.push_back(columns[i].clone());
+            linked[i] = true;
+
+            let mut current = i;
+            while let Some(next) = Column::find_next_column(columns, current, &linked) {
+                list.push_back(columns[next].clone());
+                linked[next] = true;
+                current = next;
+            }
+
+            lists.push(list);
+        }
+
+        lists
+    }
+
+    pub(crate) fn push_back(&mut self, column: Rc<RefCell<Column>>) {
+        match self.last {
+            Some(ref last) => {
+                last.borrow_mut().next_column = Some(column.clone());
+                column.borrow_mut().previous_column = Some(Rc::downgrade(last));
+            },
+            None => {
+                self.first = Some(column.clone());
+            }
+        }
+
+        self.last = Some(column);
+    }
+
+    pub(crate) fn get_first(&self) -> Option<Rc<RefCell<Column>>> {
+        self.first.clone()
+    }
+
+    pub(crate) fn get_last(&self) -> Option<Rc<RefCell<Column>>> {
+        self.last.clone()
+    }
+
+    pub(crate) fn columns(&self) -> Vec<Rc<RefCell<Column>>> {
+        let mut columns = vec![];
+        let mut current = self.first.clone();
+
+        while let Some(column) = current {
+            current = column.borrow().next_column.clone();
+            columns.push(column);
+        }
+
+        columns
+    }
+}
+
+/// Decides orientation of a region from total scores of its vertical and horizontal
+/// columns. Fixed `target` orientation always wins, otherwise the higher score is used and
+/// ties (for example a region with a single area) fall back to `default_vertical`.
+pub(crate) fn detect_orientation(vertical_score: f32, horizontal_score: f32, target: Orientation, default_vertical: bool) -> Orientation {
+    match target {
+        Orientation::Vertical | Orientation::Horizontal => target,
+        Orientation::Auto => {
+            if vertical_score > horizontal_score {
+                Orientation::Vertical
+            } else if horizontal_score > vertical_score {
+                Orientation::Horizontal
+            } else if default_vertical {
+                Orientation::Vertical
+            } else {
+                Orientation::Horizontal
+            }
+        }
+    }
+}
+
+/// Finds base characters covered by a furigana column. Returns the range of indices
+/// (start, end) into `base` whose extent in reading direction overlaps the furigana.
+pub(crate) fn furigana_span(furigana: &Rect, base: &[Rect], vertical: bool) -> Option<(usize, usize)> {
+    let (start, end) = along(furigana, vertical);
+
+    let covered: Vec<usize> = base.iter()
+        .enumerate()
+        .filter(|(_, rect)| {
+            let (base_start, base_end) = along(rect, vertical);
+            base_start < end && start < base_end
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    match (covered.first(), covered.last()) {
+        (Some(&first), Some(&last)) => Some((first, last + 1)),
+        _ => None
+    }
+}
+
+/// Range (start, end) of the rectangle in reading direction. End is exclusive.
+#[inline(always)]
+pub(crate) fn along(rect: &Rect, vertical: bool) -> (u32, u32) {
+    if vertical {
+        (rect.y, rect.y + rect.height)
+    } else {
+        (rect.x, rect.x + rect.width)
+    }
+}
+
+/// Range (start, end) of the rectangle perpendicular to reading direction. End is exclusive.
+#[inline(always)]
+pub(crate) fn across(rect: &Rect, vertical: bool) -> (u32, u32) {
+    along(rect, !vertical)
+}
+
+pub(crate) fn union(a: &Rect, b: &Rect) -> Rect {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    let max_x = (a.x + a.width).max(b.x + b.width);
+    let max_y = (a.y + a.height).max(b.y + b.height);
+
+    Rect {
+        x,
+        y,
+        width: max_x - x,
+        height: max_y - y,
+    }
+}
+
+/// Checks if the rectangles have a common pixel.
+pub(crate) fn intersects(a: &Rect, b: &Rect) -> bool {
+    a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+}
+
+/// Empty space between the rectangles, the larger of horizontal and vertical gaps. Zero if
+/// they overlap.
+fn gap(a: &Rect, b: &Rect) -> u32 {
+    let horizontal = b.x.saturating_sub(a.x + a.width).max(a.x.saturating_sub(b.x + b.width));
+    let vertical = b.y.saturating_sub(a.y + a.height).max(a.y.saturating_sub(b.y + b.height));
+
+    horizontal.max(vertical)
+}
+
+pub(crate) fn find_region(regions: &mut [usize], mut i: usize) -> usize {
+    while regions[i] != i {
+        regions[i] = regions[regions[i]];
+        i = regions[i];
+    }
+    i
+}
+
+pub(crate) fn union_regions(regions: &mut [usize], a: usize, b: usize) {
+    let a = find_region(regions, a);
+    let b = find_region(regions, b);
+    if a != b {
+        regions[b] = a;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(x: u32, y: u32, width: u32, height: u32) -> Area {
+        Area::new(Rect { x, y, width, height }, width * height)
+    }
+
+    #[test]
+    fn test_vertical_column() {
+        let areas: Vec<Area> = (0..4).map(|i| area(10, 10 + i * 24, 20, 20)).collect();
+
+        let (vertical, horizontal) = Column::find_columns(&areas, &Parameters::default());
+
+        assert_eq!(1, vertical.len());
+        assert_eq!(4, vertical[0].areas.len());
+        assert!(horizontal.is_empty());
+    }
+
+    #[test]
+    fn test_horizontal_column() {
+        let areas: Vec<Area> = (0..4).map(|i| area(10 + i * 24, 10, 20, 20)).collect();
+
+        let (vertical, horizontal) = Column::find_columns(&areas, &Parameters::default());
+
+        assert!(vertical.is_empty());
+        assert_eq!(1, horizontal.len());
+        assert_eq!(4, horizontal[0].areas.len());
+    }
+
+    #[test]
+    fn test_separate_regions() {
+        // Two vertical columns with a horizontal line of text to their left. The line would
+        // continue into the top row of the columns if regions weren't separated by the gap.
+        let mut areas: Vec<Area> = (0..4)
+            .flat_map(|i| vec![area(200, 10 + i * 24, 20, 20), area(176, 10 + i * 24, 20, 20)])
+            .collect();
+        areas.extend((0..4).map(|i| area(72 + i * 24, 10, 20, 20)));
+
+        let (vertical, horizontal) = Column::find_columns(&areas, &Parameters::default());
+
+        assert_eq!(2, vertical.len());
+        assert!(vertical.iter().all(|column| column.areas.len() == 4));
+        assert_eq!(1, horizontal.len());
+        assert_eq!(Rect { x: 72, y: 10, width: 92, height: 20 }, horizontal[0].get_rectangle());
+    }
+
+    #[test]
+    fn test_detect_orientation() {
+        assert_eq!(Orientation::Vertical, detect_orientation(2.0, 1.0, Orientation::Auto, false));
+        assert_eq!(Orientation::Horizontal, detect_orientation(1.0, 2.0, Orientation::Auto, true));
+        assert_eq!(Orientation::Vertical, detect_orientation(0.0, 0.0, Orientation::Auto, true));
+        assert_eq!(Orientation::Horizontal, detect_orientation(0.0, 0.0, Orientation::Auto, false));
+        assert_eq!(Orientation::Horizontal, detect_orientation(5.0, 0.0, Orientation::Horizontal, true));
+    }
+
+    #[test]
+    fn test_find_furigana() {
+        let main = Column::new((0..4).map(|i| area(100, 10 + i * 24, 20, 20)).collect(), true);
+        let furigana = Column::new((0..3).map(|i| area(123, 34 + i * 10, 8, 8)).collect(), true);
+        let columns = vec![Rc::new(RefCell::new(main)), Rc::new(RefCell::new(furigana))];
+
+        let columns = Column::find_furigana(columns);
+
+        assert_eq!(1, columns.len());
+        let main = columns[0].borrow();
+        assert_eq!(1, main.get_furigana_columns().len());
+        assert!(main.get_furigana_columns()[0].is_furigana());
+    }
+
+    #[test]
+    fn test_find_furigana_roles_first() {
+        // Column next to the furigana is thin enough to be its furigana, but furigana has no
+        // reading of its own
+        let thin = Column::new((0..5).map(|i| area(133, 36 + i * 5, 3, 3)).collect(), true);
+        let furigana = Column::new((0..3).map(|i| area(123, 34 + i * 10, 8, 8)).collect(), true);
+        let main = Column::new((0..4).map(|i| area(100, 10 + i * 24, 20, 20)).collect(), true);
+        let columns = vec![Rc::new(RefCell::new(thin)), Rc::new(RefCell::new(furigana)), Rc::new(RefCell::new(main))];
+
+        let columns = Column::find_furigana(columns);
+
+        assert_eq!(2, columns.len());
+        assert!(columns[0].borrow().get_furigana_columns().is_empty());
+        let main = columns[1].borrow();
+        assert_eq!(1, main.get_furigana_columns().len());
+        assert!(main.get_furigana_columns()[0].get_furigana_columns().is_empty());
+    }
+
+    #[test]
+    fn test_furigana_span() {
+        let base: Vec<Rect> = (0..4).map(|i| Rect { x: 100, y: 10 + i * 24, width: 20, height: 20 }).collect();
+        let furigana = Rect { x: 123, y: 34, width: 8, height: 28 };
+
+        assert_eq!(Some((1, 3)), furigana_span(&furigana, &base, true));
+        assert_eq!(None, furigana_span(&Rect { x: 123, y: 200, width: 8, height: 8 }, &base, true));
+    }
+
+    #[test]
+    fn test_merge_areas() {
+        let mut areas: Vec<Area> = (0..3).map(|i| area(100 + i * 8, 10, 4, 20)).collect();
+        areas.push(area(100, 34, 20, 20));
+        areas.push(area(100, 58, 20, 20));
+        let mut column = Column::new(areas, true);
+
+        column.merge_areas();
+
+        assert_eq!(3, column.areas.len());
+        assert_eq!(Rect { x: 100, y: 10, width: 20, height: 20 }, column.areas[0].get_rectangle());
+        assert_eq!(240, column.areas[0].get_pixels());
+        assert_eq!(3, column.areas[0].get_source_areas().len());
+    }
+
+    #[test]
+    fn test_merge_keeps_punctuation_and_small_kana() {
+        let mut column = Column::new(vec![
+            area(100, 0, 20, 20),
+            // Comma followed by two small kana
+            area(113, 22, 6, 6),
+            area(104, 30, 10, 10),
+            area(104, 42, 10, 10),
+            area(100, 56, 20, 20),
+            // Bars of 二
+            area(102, 80, 16, 3),
+            area(100, 90, 20, 3),
+            area(100, 97, 20, 20),
+        ], true);
+
+        column.find_punctuation();
+        column.merge_areas();
+
+        assert_eq!(7, column.areas.len());
+        assert_eq!(Some('、'), column.areas[1].get_punctuation());
+        assert_eq!(Rect { x: 104, y: 30, width: 10, height: 10 }, column.areas[2].get_rectangle());
+        assert_eq!(Rect { x: 100, y: 80, width: 20, height: 13 }, column.areas[5].get_rectangle());
+    }
+
+    #[test]
+    fn test_split_areas() {
+        let binary = DMatrix::from_fn(110, 130, |y, x| {
+            let top = x >= 100 && x < 120 && y >= 10 && y < 29;
+            let bridge = x >= 109 && x < 111 && y >= 29 && y < 31;
+            let bottom = x >= 100 && x < 120 && y >= 31 && y < 51;
+            let others = x >= 100 && x < 120 && ((y >= 55 && y < 75) || (y >= 79 && y < 99));
+            top || bridge || bottom || others
+        });
+        let from_binary = |x: u32, y: u32, width: u32, height: u32| {
+            let mask = DMatrix::from_fn(height as usize, width as usize, |row, col| binary[(y as usize + row, x as usize + col)]);
+            Area::from_mask(Rect { x, y, width, height }, mask)
+        };
+        let mut column = Column::new(vec![
+            from_binary(100, 10, 20, 41),
+            from_binary(100, 55, 20, 20),
+            from_binary(100, 79, 20, 20),
+        ], true);
+
+        column.split_areas();
+
+        assert_eq!(4, column.areas.len());
+        assert_eq!(Rect { x: 100, y: 10, width: 20, height: 20 }, column.areas[0].get_rectangle());
+        assert_eq!(Rect { x: 100, y: 30, width: 20, height: 21 }, column.areas[1].get_rectangle());
+        assert!(column.areas[0].splitted);
+        assert_eq!(1, column.areas[1].get_source_areas().len());
+    }
+
+    #[test]
+    fn test_link_columns() {
+        let right = Column::new((0..4).map(|i| area(100, 10 + i * 24, 20, 20)).collect(), true);
+        let left = Column::new((0..3).map(|i| area(70, 10 + i * 24, 20, 20)).collect(), true);
+        let columns = vec![Rc::new(RefCell::new(left)), Rc::new(RefCell::new(right))];
+
+        let lists = ColumnList::build(&columns);
+
+        assert_eq!(1, lists.len());
+        let linked = lists[0].columns();
+        assert_eq!(2, linked.len());
+        assert!(Rc::ptr_eq(&columns[1], &linked[0]));
+        assert!(Rc::ptr_eq(&columns[0], &linked[1]));
+        assert!(Rc::ptr_eq(&columns[1], &columns[0].borrow().get_previous_column().unwrap()));
+    }
+}
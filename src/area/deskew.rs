@@ -0,0 +1,199 @@
+use image::{ImageBuffer, Pixel};
+use image::math::Rect;
+use nalgebra::DMatrix;
+
+/// Angle difference between tested skew angles in degrees.
+const ANGLE_STEP: f32 = 0.25;
+
+/// Estimates the angle in degrees by which the image must be rotated to make text lines
+/// straight. Black pixels are projected to rows and columns at each tested angle; straight
+/// lines and columns produce the sharpest profiles.
+pub(crate) fn estimate_skew(binary: &DMatrix<bool>, max_angle: f32) -> f32 {
+    let (height, width) = binary.shape();
+    let pixels: Vec<(f32, f32)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|&(x, y)| binary[(y, x)])
+        .map(|(x, y)| (x as f32, y as f32))
+        .collect();
+
+    let mut best_angle = 0.0;
+    let mut best_score = profile_score(&pixels, width, height, 0.0);
+    let steps = (max_angle.abs() / ANGLE_STEP) as i32;
+
+    for step in -steps..=steps {
+        let angle = step as f32 * ANGLE_STEP;
+        if step == 0 {
+            continue;
+        }
+
+        let score = profile_score(&pixels, width, height, angle);
+        if score > best_score {
+            best_score = score;
+            best_angle = angle;
+        }
+    }
+
+    best_angle
+}
+
+/// Sum of squared row and column counts of pixels after rotating them by `angle`.
+fn profile_score(pixels: &[(f32, f32)], width: usize, height: usize, angle: f32) -> f64 {
+    // Rotated pixels stay within half of width + height from image center
+    let mut rows = vec![0u32; height + 2 * width];
+    let mut columns = vec![0u32; width + 2 * height];
+
+    for &(x, y) in pixels {
+        let (tx, ty) = to_deskewed(x, y, angle, width as u32, height as u32);
+        rows[(ty + width as f32) as usize] += 1;
+        columns[(tx + height as f32) as usize] += 1;
+    }
+
+    rows.iter().chain(columns.iter()).map(|&count| (count as f64).powi(2)).sum()
+}
+
+/// Rotates the image by `angle` around its center. Pixels outside the original image are white.
+pub(crate) fn rotate<P>(image: &ImageBuffer<P, Vec<u8>>, angle: f32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static
+{
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+
+    let mut background = *image.get_pixel(0, 0);
+    background.apply(|_| 255);
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (sx, sy) = to_original(x as f32, y as f32, angle, width, height);
+        sample(image, sx, sy, background)
+    })
+}
+
+/// Maps rectangle in the deskewed image to the bounding box of its rotated corners in the
+/// original image.
+pub(crate) fn map_rect(rect: Rect, angle: f32, width: u32, height: u32) -> Rect {
+    let corners = [
+        (rect.x, rect.y),
+        (rect.x + rect.width, rect.y),
+        (rect.x, rect.y + rect.height),
+        (rect.x + rect.width, rect.y + rect.height),
+    ];
+
+    let mut min_x = std::f32::MAX;
+    let mut min_y = std::f32::MAX;
+    let mut max_x = std::f32::MIN;
+    let mut max_y = std::f32::MIN;
+    for &(x, y) in corners.iter() {
+        let (sx, sy) = to_original(x as f32, y as f32, angle, width, height);
+        min_x = min_x.min(sx);
+        min_y = min_y.min(sy);
+        max_x = max_x.max(sx);
+        max_y = max_y.max(sy);
+    }
+
+    let x = min_x.max(0.0).round() as u32;
+    let y = min_y.max(0.0).round() as u32;
+    let end_x = (max_x.round().max(0.0) as u32).min(width);
+    let end_y = (max_y.round().max(0.0) as u32).min(height);
+
+    Rect {
+        x,
+        y,
+        width: end_x.saturating_sub(x),
+        height: end_y.saturating_sub(y),
+    }
+}
+
+/// Coordinates of the point in the original image that ends up at (x, y) after deskewing.
+pub(crate) fn to_original(x: f32, y: f32, angle: f32, width: u32, height: u32) -> (f32, f32) {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let (dx, dy) = (x - cx, y - cy);
+
+    (cos * dx - sin * dy + cx, sin * dx + cos * dy + cy)
+}
+
+/// Coordinates of the original image point (x, y) after deskewing.
+pub(crate) fn to_deskewed(x: f32, y: f32, angle: f32, width: u32, height: u32) -> (f32, f32) {
+    to_original(x, y, -angle, width, height)
+}
+
+/// Bilinear interpolation between the four pixels around (x, y).
+fn sample<P>(image: &ImageBuffer<P, Vec<u8>>, x: f32, y: f32, background: P) -> P
+where
+    P: Pixel<Subpixel = u8> + 'static
+{
+    let (width, height) = image.dimensions();
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+        return background;
+    }
+
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let top_left = image.get_pixel(x0, y0).channels();
+    let top_right = image.get_pixel(x1, y0).channels();
+    let bottom_left = image.get_pixel(x0, y1).channels();
+    let bottom_right = image.get_pixel(x1, y1).channels();
+
+    let mut pixel = background;
+    for (i, channel) in pixel.channels_mut().iter_mut().enumerate() {
+        let top = top_left[i] as f32 * (1.0 - fx) + top_right[i] as f32 * fx;
+        let bottom = bottom_left[i] as f32 * (1.0 - fx) + bottom_right[i] as f32 * fx;
+        *channel = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+
+    pixel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    /// Distance of (x, y) from a line through image center that descends by `slope`.
+    fn line_offset(x: u32, y: u32, slope: f32) -> f32 {
+        (y as f32 - 30.0) - slope * (x as f32 - 50.0)
+    }
+
+    #[test]
+    fn test_estimate_skew() {
+        let slope = 3.0_f32.to_radians().tan();
+        let binary = DMatrix::from_fn(60, 100, |y, x| {
+            let offset = line_offset(x as u32, y as u32, slope);
+            (-2..=2).any(|line| (offset - line as f32 * 10.0).abs() < 1.0)
+        });
+
+        assert!((estimate_skew(&binary, 5.0) - 3.0).abs() < 0.3);
+        assert_eq!(0.0, estimate_skew(&binary, 0.0));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let slope = 3.0_f32.to_radians().tan();
+        let image: GrayImage = ImageBuffer::from_fn(100, 60, |x, y| {
+            if line_offset(x, y, slope).abs() < 1.5 {
+                Luma([0])
+            } else {
+                Luma([255])
+            }
+        });
+
+        let rotated = rotate(&image, 3.0);
+
+        assert!(rotated.get_pixel(30, 30).0[0] < 128);
+        assert!(rotated.get_pixel(70, 30).0[0] < 128);
+        assert!(rotated.get_pixel(70, 34).0[0] > 128);
+    }
+
+    #[test]
+    fn test_map_rect() {
+        let rect = Rect { x: 40, y: 20, width: 20, height: 20 };
+
+        assert_eq!(rect, map_rect(rect, 0.0, 100, 60));
+        let mapped = map_rect(rect, 3.0, 100, 60);
+        assert!(mapped.width > 20 && mapped.height > 20);
+    }
+}
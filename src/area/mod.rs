@@ -1,12 +1,17 @@
 mod area;
 mod area_task;
 mod column;
+mod deskew;
+mod punctuation;
+mod screentone;
 
 pub(crate) use area::Area;
-pub(crate) use area_task::AreaTask;
-pub(crate) use column::Column;
+pub(crate) use area_task::{AreaTask, AreaDetector};
+pub(crate) use column::{Column, ColumnList};
+pub(crate) use screentone::suppress as suppress_screentone;
 use sealed::*;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Point {
     pub x: u32,
     pub y: u32,
@@ -0,0 +1,287 @@
+use crate::area::Area;
+use crate::area::column::{along, across};
+use crate::traits::HasRectangle;
+use image::math::Rect;
+
+/// Areas smaller than this share of column thickness are noise, not punctuation.
+const MIN_MARK_SIZE: f32 = 0.1;
+/// Maximum size of dot-like marks (、。・) relative to column thickness.
+const MAX_DOT_SIZE: f32 = 0.4;
+/// Dots this far from column center line are commas and periods, closer ones are middle dots.
+const MIN_DOT_OFFSET: f32 = 0.1;
+/// Commas and periods start this close to the end of the previous character, relative to
+/// column thickness.
+const MAX_DOT_DISTANCE: f32 = 0.5;
+/// Maximum difference between the gaps before and after a middle dot relative to column thickness.
+const MAX_DOT_CENTER_OFFSET: f32 = 0.25;
+/// Punctuation takes a cell of its own. Areas that overlap another area of the column by more
+/// than this share of their length in reading direction are parts of a character, like
+/// dakuten or the dots of 小.
+const MAX_MARK_OVERLAP: f32 = 0.5;
+/// Maximum length of brackets in reading direction relative to column thickness.
+const MAX_BRACKET_LENGTH: f32 = 0.45;
+/// Minimum width of brackets perpendicular to reading direction relative to column thickness.
+const MIN_BRACKET_WIDTH: f32 = 0.5;
+/// Brackets are thin strokes so most of their bounding box is empty.
+const MAX_BRACKET_FILL: f32 = 0.45;
+/// Minimum length of exclamation marks, question marks and ellipses relative to column thickness.
+const MIN_MARK_LENGTH: f32 = 0.5;
+/// Maximum width of exclamation marks and ellipses relative to column thickness.
+pub(crate) const MAX_THIN_WIDTH: f32 = 0.3;
+/// Maximum width of question marks relative to column thickness.
+pub(crate) const MAX_QUESTION_WIDTH: f32 = 0.6;
+/// Maximum length of the dot at the end of exclamation and question marks relative to mark length.
+const MAX_END_DOT_LENGTH: f32 = 0.25;
+/// Maximum gap between parts of a mark relative to column thickness.
+pub(crate) const MAX_MARK_GAP: f32 = 0.35;
+
+/// Classifies area at `index` of column areas as Japanese punctuation by its size, shape and
+/// position relative to the column and the areas next to it. Returns `None` for areas that
+/// should go through character matching.
+pub(crate) fn classify(areas: &[Area], index: usize, column: &Rect, thickness: u32, vertical: bool) -> Option<char> {
+    let area = &areas[index];
+    let rect = area.get_rectangle();
+    let thickness = thickness as f32;
+    let (along_start, along_end) = along(&rect, vertical);
+    let (across_start, across_end) = across(&rect, vertical);
+    let length = (along_end - along_start) as f32;
+    let width = (across_end - across_start) as f32;
+    let fill = area.get_pixels() as f32 / area.get_size() as f32;
+
+    if (area.get_max_dim() as f32) < thickness * MIN_MARK_SIZE {
+        return None;
+    }
+
+    let stroke_beside = areas.iter().enumerate().any(|(i, other)| {
+        let (other_start, other_end) = along(&other.get_rectangle(), vertical);
+        let overlap = other_end.min(along_end).saturating_sub(other_start.max(along_start));
+        i != index && overlap as f32 > length * MAX_MARK_OVERLAP
+    });
+    if stroke_beside {
+        return None;
+    }
+
+    if area.get_max_dim() as f32 <= thickness * MAX_DOT_SIZE {
+        let (column_start, column_end) = across(column, vertical);
+        let column_center = (column_start + column_end) as f32 / 2.0;
+        let center = (across_start + across_end) as f32 / 2.0;
+
+        // Gaps to the previous and the next area in reading direction
+        let gap_before = areas[..index].iter()
+            .map(|a| along(&a.get_rectangle(), vertical).1)
+            .max()
+            .map(|end| along_start.saturating_sub(end) as f32);
+        let gap_after = areas[index + 1..].iter()
+            .map(|a| along(&a.get_rectangle(), vertical).0)
+            .min()
+            .map(|start| start.saturating_sub(along_end) as f32);
+
+        // Commas and periods are in the upper right corner of vertical text and lower left
+        // corner of horizontal text, right after the previous character. Middle dots are
+        // centered in their cell.
+        return if center > column_center + thickness * MIN_DOT_OFFSET {
+            if gap_before.map_or(false, |gap| gap > thickness * MAX_DOT_DISTANCE) {
+                None
+            } else if is_ring(area) {
+                Some('。')
+            } else {
+                Some('、')
+            }
+        } else if center >= column_center - thickness * MIN_DOT_OFFSET {
+            match (gap_before, gap_after) {
+                (Some(before), Some(after)) if (before - after).abs() > thickness * MAX_DOT_CENTER_OFFSET => None,
+                _ => Some('・')
+            }
+        } else {
+            None
+        };
+    }
+
+    if length <= thickness * MAX_BRACKET_LENGTH && width >= thickness * MIN_BRACKET_WIDTH && fill <= MAX_BRACKET_FILL {
+        let band = ((length / 4.0).ceil() as u32).max(1);
+        let opening = band_pixels(area, vertical, along_start, along_start + band)
+            > band_pixels(area, vertical, along_end - band, along_end);
+        let double = center_runs(area, vertical).len() >= 2;
+
+        return match (opening, double) {
+            (true, false) => Some('「'),
+            (false, false) => Some('」'),
+            (true, true) => Some('『'),
+            (false, true) => Some('』'),
+        };
+    }
+
+    if length >= thickness * MIN_MARK_LENGTH && width <= thickness * MAX_QUESTION_WIDTH {
+        let runs = center_runs(area, vertical);
+        let ends_with_dot = runs.len() == 2 && {
+            let (start, end) = runs[1];
+            ((end - start) as f32) <= length * MAX_END_DOT_LENGTH
+        };
+
+        if width <= thickness * MAX_THIN_WIDTH {
+            if runs.len() >= 3 {
+                return Some('…');
+            }
+            if ends_with_dot {
+                return Some('！');
+            }
+        } else if ends_with_dot {
+            return Some('？');
+        }
+    }
+
+    None
+}
+
+/// Small area with an empty center is a period.
+fn is_ring(area: &Area) -> bool {
+    let rect = area.get_rectangle();
+    if rect.width < 4 || rect.height < 4 {
+        return false;
+    }
+
+    let ratio = rect.width.min(rect.height) as f32 / rect.width.max(rect.height) as f32;
+    ratio >= 0.6 && !area.is_black(rect.x + rect.width / 2, rect.y + rect.height / 2)
+}
+
+/// Counts black pixels inside rect between `start` and `end` in reading direction.
+fn band_pixels(area: &Area, vertical: bool, start: u32, end: u32) -> u32 {
+    let (across_start, across_end) = across(&area.get_rectangle(), vertical);
+    let mut pixels = 0;
+
+    for a in start..end {
+        for c in across_start..across_end {
+            if is_black(area, a, c, vertical) {
+                pixels += 1;
+            }
+        }
+    }
+
+    pixels
+}
+
+/// Runs (start, end) of black pixels along the center line of rect in reading direction.
+/// Center line is three pixels wide so that thin strokes aren't missed.
+fn center_runs(area: &Area, vertical: bool) -> Vec<(u32, u32)> {
+    let rect = area.get_rectangle();
+    let (along_start, along_end) = along(&rect, vertical);
+    let (across_start, across_end) = across(&rect, vertical);
+    let center = (across_start + across_end) / 2;
+    let band_start = center.saturating_sub(1).max(across_start);
+    let band_end = (center + 2).min(across_end);
+
+    let mut runs = vec![];
+    let mut run_start = None;
+
+    for a in along_start..along_end {
+        let black = (band_start..band_end).any(|c| is_black(area, a, c, vertical));
+        match (black, run_start) {
+            (true, None) => run_start = Some(a),
+            (false, Some(start)) => {
+                runs.push((start, a));
+                run_start = None;
+            },
+            _ => ()
+        }
+    }
+
+    if let Some(start) = run_start {
+        runs.push((start, along_end));
+    }
+
+    runs
+}
+
+#[inline(always)]
+fn is_black(area: &Area, along: u32, across: u32, vertical: bool) -> bool {
+    if vertical {
+        area.is_black(across, along)
+    } else {
+        area.is_black(along, across)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::DMatrix;
+
+    const COLUMN: Rect = Rect { x: 0, y: 0, width: 30, height: 60 };
+
+    fn build_area(binary: &DMatrix<bool>, rect: Rect) -> Area {
+        let mask = DMatrix::from_fn(rect.height as usize, rect.width as usize, |row, col| {
+            binary[(rect.y as usize + row, rect.x as usize + col)]
+        });
+
+        Area::from_mask(rect, mask)
+    }
+
+    fn classify_all(binary: &DMatrix<bool>, rects: &[Rect]) -> Vec<Option<char>> {
+        let areas: Vec<Area> = rects.iter().map(|&rect| build_area(binary, rect)).collect();
+        (0..areas.len()).map(|i| classify(&areas, i, &COLUMN, 30, true)).collect()
+    }
+
+    #[test]
+    fn test_dots() {
+        let binary = DMatrix::from_fn(60, 30, |y, x| {
+            let comma = x >= 22 && x < 28 && y < 6;
+            let period = x >= 20 && x < 28 && y >= 10 && y < 18 && (x == 20 || x == 27 || y == 10 || y == 17);
+            let middle_dot = x >= 13 && x < 18 && y >= 20 && y < 25;
+            comma || period || middle_dot
+        });
+
+        assert_eq!(vec![Some('、'), Some('。'), Some('・')], classify_all(&binary, &[
+            Rect { x: 22, y: 0, width: 6, height: 6 },
+            Rect { x: 20, y: 10, width: 8, height: 8 },
+            Rect { x: 13, y: 20, width: 5, height: 5 },
+        ]));
+    }
+
+    #[test]
+    fn test_dots_of_characters() {
+        let binary = DMatrix::from_fn(60, 30, |y, x| {
+            // Kana with dakuten in the upper right corner
+            let kana = x >= 2 && x < 20 && y < 26 && (x == 2 || y == 10);
+            let dakuten = x >= 23 && x < 27 && y >= 1 && y < 5;
+            // Dot on the left side of the column and a comma far below the previous character
+            let left_dot = x >= 3 && x < 7 && y >= 30 && y < 34;
+            let far_comma = x >= 22 && x < 28 && y >= 52 && y < 58;
+            kana || dakuten || left_dot || far_comma
+        });
+
+        assert_eq!(vec![None, None, None, None], classify_all(&binary, &[
+            Rect { x: 2, y: 0, width: 18, height: 26 },
+            Rect { x: 23, y: 1, width: 4, height: 4 },
+            Rect { x: 3, y: 30, width: 4, height: 4 },
+            Rect { x: 22, y: 52, width: 6, height: 6 },
+        ]));
+    }
+
+    #[test]
+    fn test_brackets() {
+        let binary = DMatrix::from_fn(60, 30, |y, x| {
+            let opening = x >= 5 && x < 25 && y < 10 && (y < 2 || x >= 23);
+            let closing = x >= 5 && x < 25 && y >= 20 && y < 30 && (y >= 28 || x < 7);
+            opening || closing
+        });
+
+        assert_eq!(vec![Some('「'), Some('」')], classify_all(&binary, &[
+            Rect { x: 5, y: 0, width: 20, height: 10 },
+            Rect { x: 5, y: 20, width: 20, height: 10 },
+        ]));
+    }
+
+    #[test]
+    fn test_exclamation_mark() {
+        let binary = DMatrix::from_fn(60, 30, |y, x| x >= 13 && x < 17 && (y < 18 || (y >= 22 && y < 26)));
+
+        assert_eq!(vec![Some('！')], classify_all(&binary, &[Rect { x: 13, y: 0, width: 4, height: 26 }]));
+    }
+
+    #[test]
+    fn test_character_is_not_punctuation() {
+        let binary = DMatrix::from_fn(60, 30, |y, x| x >= 2 && x < 28 && y < 26 && (x % 5 == 0 || y % 5 == 0));
+
+        assert_eq!(vec![None], classify_all(&binary, &[Rect { x: 2, y: 0, width: 26, height: 26 }]));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::util::{make_bw, matrix_from_image};
+use image::{ImageBuffer, Pixel, FromColor, Rgba};
+use nalgebra::DMatrix;
+
+/// Size of the tiles in which dot spacing is compared.
+const TILE_SIZE: usize = 48;
+/// Minimum number of dots in a tile for it to be considered screentone.
+const MIN_TILE_DOTS: usize = 8;
+/// Maximum distance of a dot center from the nearest lattice point relative to dot spacing.
+const MAX_LATTICE_OFFSET: f32 = 0.2;
+
+/// Small connected component, candidate for a halftone dot.
+struct Dot {
+    x: f32,
+    y: f32,
+    pixels: Vec<(usize, usize)>,
+}
+
+/// Paints halftone dots white. Dots are connected components smaller than `max_dot_size`
+/// that lie on the regular lattice found inside a tile. Anything larger, including strokes
+/// that touch the pattern, and small marks between the lattice points, like dakuten, are
+/// kept so that characters stay intact. Dots are found from the image binarized with
+/// `black_threshold`.
+pub(crate) fn suppress<P>(image: &mut ImageBuffer<P, Vec<u8>>, max_dot_size: u32, black_threshold: u8)
+where
+    P: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
+{
+    let binary = matrix_from_image(&make_bw(&*image, black_threshold));
+    let dots = find_dots(&binary, max_dot_size as usize);
+
+    let tiles_x = (binary.ncols() + TILE_SIZE - 1) / TILE_SIZE;
+    let tiles_y = (binary.nrows() + TILE_SIZE - 1) / TILE_SIZE;
+    let mut tiles: Vec<Vec<&Dot>> = vec![vec![]; tiles_x * tiles_y];
+
+    for dot in &dots {
+        tiles[(dot.y as usize / TILE_SIZE) * tiles_x + dot.x as usize / TILE_SIZE].push(dot);
+    }
+
+    for tile in &tiles {
+        let lattice = match Lattice::find(tile) {
+            Some(lattice) => lattice,
+            None => continue
+        };
+
+        for dot in tile.iter().filter(|dot| lattice.contains(dot)) {
+            for &(y, x) in &dot.pixels {
+                image.get_pixel_mut(x as u32, y as u32).apply(|_| 255);
+            }
+        }
+    }
+}
+
+/// Finds 8-connected components that fit inside `max_dot_size` square.
+fn find_dots(binary: &DMatrix<bool>, max_dot_size: usize) -> Vec<Dot> {
+    let (height, width) = binary.shape();
+    let mut visited = DMatrix::from_element(height, width, false);
+    let mut dots = vec![];
+    let mut stack = vec![];
+
+    for y in 0..height {
+        for x in 0..width {
+            if visited[(y, x)] || !binary[(y, x)] {
+                continue;
+            }
+
+            visited[(y, x)] = true;
+            stack.push((y, x));
+
+            let mut pixels = vec![];
+            let (mut min_x, mut max_x, mut min_y, mut max_y) = (x, x, y, y);
+
+            while let Some((py, px)) = stack.pop() {
+                pixels.push((py, px));
+                min_x = min_x.min(px);
+                max_x = max_x.max(px);
+                min_y = min_y.min(py);
+                max_y = max_y.max(py);
+
+                for ny in py.saturating_sub(1)..=(py + 1).min(height - 1) {
+                    for nx in px.saturating_sub(1)..=(px + 1).min(width - 1) {
+                        if !visited[(ny, nx)] && binary[(ny, nx)] {
+                            visited[(ny, nx)] = true;
+                            stack.push((ny, nx));
+                        }
+                    }
+                }
+            }
+
+            if max_x - min_x < max_dot_size && max_y - min_y < max_dot_size {
+                dots.push(Dot {
+                    x: (min_x + max_x) as f32 / 2.0,
+                    y: (min_y + max_y) as f32 / 2.0,
+                    pixels,
+                });
+            }
+        }
+    }
+
+    dots
+}
+
+/// Regular grid of halftone dots, `origin + a * first + b * second` for integers a and b.
+struct Lattice {
+    origin: (f32, f32),
+    first: (f32, f32),
+    second: (f32, f32),
+    tolerance: f32,
+}
+
+impl Lattice {
+    /// Fits a lattice to the dots of a tile. Spacing is the median distance to the nearest
+    /// dot, basis vectors are the two shortest non-parallel vectors that are common between
+    /// neighbouring dots and origin is the dot that puts most dots on the lattice. Returns
+    /// `None` unless at least `MIN_TILE_DOTS` dots are on the lattice.
+    fn find(dots: &[&Dot]) -> Option<Self> {
+        if dots.len() < MIN_TILE_DOTS {
+            return None;
+        }
+
+        let mut nearest: Vec<f32> = dots.iter()
+            .enumerate()
+            .map(|(i, a)| {
+                dots.iter()
+                    .enumerate()
+                    .filter(|&(j, _)| i != j)
+                    .map(|(_, b)| length((b.x - a.x, b.y - a.y)))
+                    .fold(std::f32::MAX, f32::min)
+            })
+            .collect();
+        nearest.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let spacing = nearest[nearest.len() / 2];
+        if spacing <= 0.0 {
+            return None;
+        }
+        let tolerance = spacing * MAX_LATTICE_OFFSET;
+
+        // Vectors between neighbouring dots, pointed to the right (or down) and grouped by
+        // direction and length
+        let mut groups: Vec<((f32, f32), (f32, f32), usize)> = vec![];
+        for a in dots {
+            for b in dots {
+                let vector = (b.x - a.x, b.y - a.y);
+                if vector.0 < 0.0 || (vector.0 == 0.0 && vector.1 <= 0.0) || length(vector) > spacing * 1.5 {
+                    continue;
+                }
+
+                match groups.iter_mut().find(|(start, _, _)| length((start.0 - vector.0, start.1 - vector.1)) <= tolerance) {
+                    Some((_, sum, count)) => {
+                        *sum = (sum.0 + vector.0, sum.1 + vector.1);
+                        *count += 1;
+                    },
+                    None => groups.push((vector, vector, 1))
+                }
+            }
+        }
+
+        let mut vectors: Vec<(f32, f32)> = groups.into_iter()
+            .filter(|&(_, _, count)| count >= MIN_TILE_DOTS / 2)
+            .map(|(_, sum, count)| (sum.0 / count as f32, sum.1 / count as f32))
+            .collect();
+        vectors.sort_by(|a, b| length(*a).partial_cmp(&length(*b)).unwrap());
+
+        let first = *vectors.first()?;
+        let second = *vectors.iter().find(|v| {
+            (first.0 * v.1 - first.1 * v.0).abs() > 0.5 * length(first) * length(**v)
+        })?;
+
+        dots.iter()
+            .map(|dot| Lattice { origin: (dot.x, dot.y), first, second, tolerance })
+            .map(|lattice| {
+                let count = dots.iter().filter(|dot| lattice.contains(dot)).count();
+                (lattice, count)
+            })
+            .max_by_key(|&(_, count)| count)
+            .filter(|&(_, count)| count >= MIN_TILE_DOTS)
+            .map(|(lattice, _)| lattice)
+    }
+
+    /// Checks if dot center is close to a lattice point.
+    fn contains(&self, dot: &Dot) -> bool {
+        let (first, second) = (self.first, self.second);
+        let d = (dot.x - self.origin.0, dot.y - self.origin.1);
+        let determinant = first.0 * second.1 - first.1 * second.0;
+        let a = ((d.0 * second.1 - d.1 * second.0) / determinant).round();
+        let b = ((first.0 * d.1 - first.1 * d.0) / determinant).round();
+        let offset = (d.0 - a * first.0 - b * second.0, d.1 - a * first.1 - b * second.1);
+
+        length(offset) <= self.tolerance
+    }
+}
+
+fn length(vector: (f32, f32)) -> f32 {
+    (vector.0 * vector.0 + vector.1 * vector.1).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    #[test]
+    fn test_suppress_screentone() {
+        let mut image = RgbaImage::from_fn(96, 96, |x, y| {
+            let dot = x % 6 >= 1 && x % 6 < 3 && y % 6 >= 1 && y % 6 < 3;
+            let stroke = x >= 40 && x < 44 && y >= 10 && y < 70;
+            if dot || stroke {
+                BLACK
+            } else {
+                WHITE
+            }
+        });
+
+        suppress(&mut image, 6, 140);
+
+        assert_eq!(&WHITE, image.get_pixel(1, 1));
+        assert_eq!(&WHITE, image.get_pixel(50, 80));
+        assert_eq!(&BLACK, image.get_pixel(41, 40));
+    }
+
+    #[test]
+    fn test_keep_scattered_dots() {
+        let mut image = RgbaImage::from_fn(48, 48, |x, y| {
+            let dot = (x >= 3 && x < 6 && y >= 4 && y < 7) || (x >= 30 && x < 32 && y >= 20 && y < 22);
+            if dot {
+                BLACK
+            } else {
+                WHITE
+            }
+        });
+
+        suppress(&mut image, 6, 140);
+
+        assert_eq!(&BLACK, image.get_pixel(4, 5));
+        assert_eq!(&BLACK, image.get_pixel(30, 20));
+    }
+
+    #[test]
+    fn test_keep_dots_between_lattice_points() {
+        let mut image = RgbaImage::from_fn(48, 48, |x, y| {
+            let dot = x % 6 >= 1 && x % 6 < 3 && y % 6 >= 1 && y % 6 < 3;
+            let dakuten = (x == 28 || x == 34) && y == 28;
+            if dot || dakuten {
+                BLACK
+            } else {
+                WHITE
+            }
+        });
+
+        suppress(&mut image, 6, 140);
+
+        assert_eq!(&WHITE, image.get_pixel(1, 1));
+        assert_eq!(&WHITE, image.get_pixel(44, 44));
+        assert_eq!(&BLACK, image.get_pixel(28, 28));
+        assert_eq!(&BLACK, image.get_pixel(34, 28));
+    }
+}
@@ -0,0 +1,103 @@
+use kanjitomo_rs::{KanjitomoError, Parameters, read_references};
+use kanjitomo_rs::tuning::{Dataset, Tuner};
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage: tune <dataset dir> [--references <dir>] [--preset <name> | --config <file>] [--output <file>] [--rounds <n>]";
+
+struct Options {
+    dataset: String,
+    references: Option<String>,
+    preset: Option<String>,
+    config: Option<String>,
+    output: Option<String>,
+    rounds: Option<usize>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut dataset = None;
+    let mut options = Options {
+        dataset: String::new(),
+        references: None,
+        preset: None,
+        config: None,
+        output: None,
+        rounds: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--references" => options.references = Some(value(&mut args, &arg)?),
+            "--preset" => options.preset = Some(value(&mut args, &arg)?),
+            "--config" => options.config = Some(value(&mut args, &arg)?),
+            "--output" => options.output = Some(value(&mut args, &arg)?),
+            "--rounds" => {
+                let rounds = value(&mut args, &arg)?;
+                options.rounds = Some(rounds.parse().map_err(|_| "--rounds must be a number".to_owned())?)
+            },
+            other if dataset.is_none() && !other.starts_with("--") => dataset = Some(other.to_owned()),
+            other => return Err(format!("unexpected argument {}", other))
+        }
+    }
+
+    options.dataset = dataset.ok_or_else(|| "dataset directory is missing".to_owned())?;
+    // Configuration files set every parameter, so a preset would be silently overwritten
+    if options.preset.is_some() && options.config.is_some() {
+        return Err("--preset and --config can't be used together".to_owned());
+    }
+    Ok(options)
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", name))
+}
+
+fn run(options: Options) -> Result<(), KanjitomoError> {
+    let base = match (&options.config, &options.preset) {
+        (Some(config), _) => Parameters::load(config)?,
+        (None, Some(preset)) => Parameters::preset(preset)?,
+        (None, None) => Parameters::default()
+    };
+
+    let dataset = Dataset::load(&options.dataset)?;
+    println!("Loaded {} images with {} characters", dataset.samples.len(), dataset.character_count());
+
+    let mut tuner = Tuner::new(dataset, base);
+    if let Some(references) = &options.references {
+        let references = read_references(references)?;
+        println!("Loaded {} reference characters", references.len());
+        tuner = tuner.references(references);
+    }
+    if let Some(rounds) = options.rounds {
+        tuner = tuner.max_rounds(rounds);
+    }
+
+    let result = tuner.run()?;
+    println!("Character error rate {:.4} after {} evaluations", result.error_rate, result.evaluations);
+
+    match options.output {
+        Some(output) => {
+            result.parameters.save(&output)?;
+            println!("Saved parameters to {}", output);
+        },
+        None => println!("{}", result.parameters.to_toml()?)
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(error) = run(options) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
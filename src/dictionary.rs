@@ -0,0 +1,260 @@
+use crate::Word;
+use crate::error::KanjitomoError;
+use crate::ocr::OCRTask;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::fs::File;
+use std::io::BufReader;
+use flate2::read::GzDecoder;
+
+/// Word list used to rerank OCR candidates. Both kanji and kana forms of every word are
+/// indexed, together with all of their prefixes so that candidate sequences that can't
+/// become a word are pruned early.
+#[derive(Debug, Default)]
+pub(crate) struct Dictionary {
+    words: Vec<Word>,
+    index: HashMap<String, Vec<usize>>,
+    prefixes: HashSet<String>,
+}
+
+impl Dictionary {
+    /// Number of best candidates of each character that are tried in words.
+    const MAX_CANDIDATES: usize = 10;
+    /// Number of best partial words that are kept when a word is extended by a character.
+    const BEAM_WIDTH: usize = 32;
+
+    pub(crate) fn new(words: Vec<Word>) -> Self {
+        let mut dictionary = Self::default();
+
+        for word in words {
+            dictionary.add_word(word);
+        }
+
+        dictionary
+    }
+
+    /// Loads gzip compressed, bincode serialized list of words.
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Self, KanjitomoError> {
+        let file = File::open(path).map_err(KanjitomoError::IOError)?;
+        let decoder = GzDecoder::new(BufReader::new(file));
+        let words: Vec<Word> = bincode::deserialize_from(decoder).map_err(KanjitomoError::DictionaryError)?;
+
+        Ok(Self::new(words))
+    }
+
+    fn add_word(&mut self, word: Word) {
+        let word_index = self.words.len();
+
+        for key in [&word.kanji, &word.kana].iter() {
+            if key.is_empty() {
+                continue;
+            }
+
+            let mut prefix = String::new();
+            for c in key.chars() {
+                prefix.push(c);
+                self.prefixes.insert(prefix.clone());
+            }
+
+            let indices = self.index.entry((*key).clone()).or_insert_with(Vec::new);
+            if !indices.contains(&word_index) {
+                indices.push(word_index);
+            }
+        }
+
+        self.words.push(word);
+    }
+
+    pub(crate) fn contains(&self, word: &str) -> bool {
+        self.index.contains_key(word)
+    }
+
+    pub(crate) fn is_prefix(&self, prefix: &str) -> bool {
+        self.prefixes.contains(prefix)
+    }
+
+    pub(crate) fn get_words(&self, word: &str) -> Vec<&Word> {
+        match self.index.get(word) {
+            None => vec![],
+            Some(indices) => indices.iter().map(|&i| &self.words[i]).collect()
+        }
+    }
+
+    /// Multiplies the score of every candidate that is part of a dictionary word (at least two
+    /// characters long) by `bias` and resorts the results of each task.
+    ///
+    /// Tasks must be in reading order, one task per character. Punctuation has no task, so
+    /// words don't continue over a gap in `char_index`.
+    pub(crate) fn apply_bias(&self, tasks: &mut [OCRTask], bias: f32, max_characters: usize) {
+        let mut start = 0;
+        for end in 1..=tasks.len() {
+            let consecutive = end < tasks.len() && match (tasks[end - 1].char_index, tasks[end].char_index) {
+                (Some(previous), Some(index)) => index == previous + 1,
+                _ => true
+            };
+
+            if !consecutive {
+                self.apply_bias_to_run(&mut tasks[start..end], bias, max_characters);
+                start = end;
+            }
+        }
+    }
+
+    fn apply_bias_to_run(&self, tasks: &mut [OCRTask], bias: f32, max_characters: usize) {
+        let mut biased: Vec<HashSet<usize>> = vec![HashSet::new(); tasks.len()];
+
+        for start in 0..tasks.len() {
+            self.find_words(tasks, start, &mut biased, max_characters);
+        }
+
+        for (task, results) in tasks.iter_mut().zip(biased) {
+            if results.is_empty() {
+                continue;
+            }
+
+            for i in results {
+                task.results[i].apply_bias(bias);
+            }
+            task.sort_results();
+        }
+    }
+
+    /// Marks candidates of words that start at `start`. Words are extended one character at
+    /// a time with the `MAX_CANDIDATES` best candidates of the next task, only prefixes of
+    /// dictionary words are extended and `BEAM_WIDTH` best of them are kept, so long runs of
+    /// similar characters such as kana don't explode the search.
+    fn find_words(&self, tasks: &[OCRTask], start: usize, biased: &mut [HashSet<usize>], max_characters: usize) {
+        // Partial word, its candidates as (task, result) indices and the sum of their scores
+        let mut paths: Vec<(String, Vec<(usize, usize)>, u32)> = vec![(String::new(), vec![], 0)];
+
+        for position in start..tasks.len().min(start + max_characters) {
+            let mut extended = vec![];
+
+            for (word, path, score) in &paths {
+                for (i, result) in tasks[position].results.iter().enumerate().take(Self::MAX_CANDIDATES) {
+                    let mut word = word.clone();
+                    word.push(result.get_character());
+                    if !self.is_prefix(&word) {
+                        continue;
+                    }
+
+                    let mut path = path.clone();
+                    path.push((position, i));
+                    if path.len() > 1 && self.contains(&word) {
+                        for &(task_index, result_index) in path.iter() {
+                            biased[task_index].insert(result_index);
+                        }
+                    }
+
+                    extended.push((word, path, score + result.score));
+                }
+            }
+
+            if extended.is_empty() {
+                return;
+            }
+
+            extended.sort_by(|a, b| b.2.cmp(&a.2));
+            extended.truncate(Self::BEAM_WIDTH);
+            paths = extended;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ocr::{OCRResult, ReferenceMatrix, TargetMatrix};
+    use crate::parameters::Parameters;
+    use image::RgbaImage;
+    use std::time::{Duration, Instant};
+
+    fn build_task(candidates: &[(char, u32)]) -> OCRTask {
+        let mut task = OCRTask::new(RgbaImage::new(1, 1), &Parameters::default());
+
+        for &(character, score) in candidates {
+            let reference = ReferenceMatrix {
+                character,
+                ..Default::default()
+            };
+            let mut result = OCRResult::new(TargetMatrix::default(), reference);
+            result.score = score;
+            task.results.push(result);
+        }
+
+        task
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let dictionary = Dictionary::new(vec![
+            Word::new("武士".to_owned(), "ぶし".to_owned(), "samurai (P)".to_owned(), false)
+        ]);
+
+        assert!(dictionary.contains("武士"));
+        assert!(dictionary.contains("ぶし"));
+        assert!(dictionary.is_prefix("武"));
+        assert!(!dictionary.contains("武"));
+        assert_eq!(1, dictionary.get_words("ぶし").len());
+    }
+
+    #[test]
+    fn test_apply_bias() {
+        let dictionary = Dictionary::new(vec![
+            Word::new("武士".to_owned(), "ぶし".to_owned(), "samurai (P)".to_owned(), false)
+        ]);
+
+        let mut tasks = vec![
+            build_task(&[('式', 920), ('武', 880)]),
+            build_task(&[('士', 900), ('土', 895)]),
+        ];
+
+        dictionary.apply_bias(&mut tasks, 1.05, 8);
+
+        assert_eq!(Some('武'), tasks[0].get_character());
+        assert_eq!(Some('士'), tasks[1].get_character());
+        assert_eq!(924, tasks[0].results[0].score);
+        assert_eq!(920, tasks[0].results[1].score);
+    }
+
+    #[test]
+    fn test_apply_bias_per_run() {
+        let dictionary = Dictionary::new(vec![
+            Word::new("武士".to_owned(), "ぶし".to_owned(), "samurai (P)".to_owned(), false)
+        ]);
+
+        // Punctuation between the characters leaves a gap in the indices
+        let mut tasks = vec![
+            build_task(&[('式', 920), ('武', 880)]),
+            build_task(&[('士', 900), ('土', 895)]),
+        ];
+        tasks[0].char_index = Some(0);
+        tasks[1].char_index = Some(2);
+
+        dictionary.apply_bias(&mut tasks, 1.05, 8);
+
+        assert_eq!(Some('式'), tasks[0].get_character());
+        assert_eq!(880, tasks[0].results[1].score);
+        assert_eq!(900, tasks[1].results[0].score);
+    }
+
+    #[test]
+    fn test_apply_bias_long_kana_run() {
+        let dictionary = Dictionary::new(vec![
+            Word::new("ああああああああ".to_owned(), "ああああああああ".to_owned(), "test".to_owned(), false)
+        ]);
+
+        // Every candidate of every character continues the word
+        let candidates: Vec<(char, u32)> = (0..50).map(|i| ('あ', 900 - i)).collect();
+        let mut tasks: Vec<OCRTask> = (0..100).map(|_| build_task(&candidates)).collect();
+
+        let started = Instant::now();
+        dictionary.apply_bias(&mut tasks, 1.05, 8);
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(tasks.iter().all(|task| task.results[0].score == 945));
+        assert!(tasks.iter().all(|task| {
+            task.results.iter().filter(|result| result.score > 900).count() <= Dictionary::MAX_CANDIDATES
+        }));
+    }
+}
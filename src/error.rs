@@ -9,6 +9,63 @@ pub enum KanjitomoError {
         min_source_value: f32,
         max_source_value: f32
     },
+    #[error("IO error: {0}")]
+    IOError(std::io::Error),
+    #[error("Could not read dictionary: {0}")]
+    DictionaryError(bincode::Error),
+    #[error("Invalid configuration at {path}: {message}")]
+    ConfigError {
+        path: String,
+        message: String
+    },
+    #[error("Reference fonts mismatch: {fonts} fonts but {bold} bold flags")]
+    FontsMismatch {
+        fonts: usize,
+        bold: usize
+    },
+    #[error("Halo scores mismatch: {field} has {found} entries but ocr_halo_size is {expected}")]
+    HaloScoresMismatch {
+        field: &'static str,
+        expected: usize,
+        found: usize
+    },
+    #[error("Target size {target_size} is larger than reference matrix size {max}")]
+    TargetSizeTooLarge {
+        target_size: u32,
+        max: u32
+    },
+    #[error("ocr_keep_results_lvl2 ({lvl2}) must not exceed ocr_keep_results_lvl1 ({lvl1})")]
+    KeepResultsMismatch {
+        lvl1: u8,
+        lvl2: u8
+    },
+    #[error("Invalid dataset labels in {path} at line {line}: {message}")]
+    DatasetError {
+        path: String,
+        line: usize,
+        message: String
+    },
+    #[error("OCR task was aborted before it was finished")]
+    TaskAborted,
+    #[error("OCR request was cancelled")]
+    Cancelled,
+    #[error("Unknown preset: {0}")]
+    UnknownPreset(String),
+    #[error("Target image is empty: {width}x{height}")]
+    EmptyImage {
+        width: u32,
+        height: u32
+    },
+    #[error("tile_overlap ({overlap}) must be smaller than tile_size ({tile_size})")]
+    TileOverlapTooLarge {
+        tile_size: u32,
+        overlap: u32
+    },
+    #[error("Parameter {field} must be at least {min}")]
+    ParameterTooSmall {
+        field: &'static str,
+        min: u32
+    },
     #[error("Something unexpected happened: {0}")]
     Custom(String)
 }
\ No newline at end of file
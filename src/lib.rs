@@ -1,51 +1,371 @@
 #![allow(dead_code, unused)]
 mod area;
+mod dictionary;
 mod error;
 mod ocr;
 mod traits;
 mod util;
 mod parameters;
+mod preprocess;
+mod presets;
+mod target_image;
+pub mod tuning;
 
-use crate::area::Point;
+pub use crate::area::Point;
+use crate::area::{AreaTask, AreaDetector};
 use num_traits::Num;
 use serde::{Serialize, Deserialize};
-use parameters::Parameters;
-use lazy_static::lazy_static;
+pub use crate::parameters::Parameters;
+pub use crate::presets::Presets;
 use crate::util::is_kanji;
-use crate::ocr::OCRManager;
-
-lazy_static! {
-    pub static ref PARAMETERS: Parameters = Default::default();
-}
+use crate::ocr::{Client, OCR, OCRManager, OCRTask, TaskHandle};
+use crate::dictionary::Dictionary;
+use crate::traits::HasRectangle;
+pub use crate::error::KanjitomoError;
+pub use crate::ocr::{CancellationToken, Priority, Workers, read_references};
+pub use crate::target_image::{TargetImage, Rgb32FImage, Rgba32FImage};
+pub use crate::preprocess::{PreprocessStage, Pipeline, Unsharp, Threshold, Invert, Denoise, Scale, Screentone};
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use image::{DynamicImage, Rgba, RgbaImage};
+use image::imageops::FilterType;
 
 pub struct KanjiTomo {
-    ocr: OCRManager,
+    parameters: Arc<Parameters>,
+    ocr: Arc<OCRManager>,
+    /// Client of this instance on `ocr`, unregistered when the instance and every `Workers`
+    /// handle of the client have been dropped.
+    client: Arc<Client>,
+    priority: Priority,
+    dictionary: Option<Dictionary>,
+    area_task: Option<AreaTask<Rgba<u8>>>,
+    pipeline: Arc<Pipeline>,
+    matcher: Arc<OCR>,
 }
 
 impl KanjiTomo {
-    pub fn new() {
+    pub fn new() -> Self {
+        let parameters = Arc::new(Parameters::default());
+        let pipeline = Pipeline::from_parameters(&parameters);
+
+        Self::build(parameters, pipeline)
+    }
+
+    /// Creates instance with its own parameters. Instances don't share any settings, so
+    /// differently configured instances can run side by side. Fails if parameters don't
+    /// pass `Parameters::validate`.
+    pub fn with_parameters(parameters: Parameters) -> Result<Self, KanjitomoError> {
+        parameters.validate()?;
+        let parameters = Arc::new(parameters);
+        let pipeline = Pipeline::from_parameters(&parameters);
+
+        Ok(Self::build(parameters, pipeline))
+    }
+
+    /// Creates instance with the parameters of built-in preset `name`, see `Presets`. Custom
+    /// presets can be used with `with_parameters(presets.get(name)?)`.
+    pub fn with_preset(name: &str) -> Result<Self, KanjitomoError> {
+        Self::with_parameters(Parameters::preset(name)?)
+    }
+
+    /// Creates instance that preprocesses images with `pipeline` instead of the one configured
+    /// in `Parameters::preprocessing`. Character images are still preprocessed as described
+    /// in `Pipeline::for_ocr`.
+    pub fn with_pipeline(parameters: Parameters, pipeline: Pipeline) -> Result<Self, KanjitomoError> {
+        parameters.validate()?;
+
+        Ok(Self::build(Arc::new(parameters), pipeline))
+    }
+
+    fn build(parameters: Arc<Parameters>, pipeline: Pipeline) -> Self {
+        let pipeline = Arc::new(pipeline);
+        let ocr = Arc::new(OCRManager::new(parameters.ocr_threads));
+
+        Self {
+            client: OCRManager::register_client(&ocr),
+            ocr,
+            priority: Priority::Interactive,
+            matcher: Arc::new(OCR::new(parameters.clone(), Arc::new(Pipeline::for_ocr(&parameters)))),
+            parameters,
+            dictionary: None,
+            area_task: None,
+            pipeline,
+        }
+    }
+
+    /// Adds reference character that OCR results are matched against. Image should contain
+    /// only the character, margins around it are cropped.
+    pub fn add_reference<I: Into<TargetImage>>(&mut self, character: char, image: I) {
+        let image = DynamicImage::ImageRgba8(image.into().to_rgba()).to_luma();
+        Arc::make_mut(&mut self.matcher).add_reference(character, &image);
+    }
+
+    /// Adds every reference character image in `dir`, see `read_references`. Returns the
+    /// number of references added.
+    pub fn load_references<P: AsRef<Path>>(&mut self, dir: P) -> Result<usize, KanjitomoError> {
+        let references = read_references(dir)?;
+        let count = references.len();
+        for (character, image) in references {
+            self.add_reference(character, image);
+        }
+
+        Ok(count)
+    }
+
+    /// Loads the dictionary used to prefer candidate characters that form known words.
+    pub fn load_dictionary<P: AsRef<Path>>(&mut self, path: P) -> Result<(), KanjitomoError> {
+        self.dictionary = Some(Dictionary::load(path)?);
+        Ok(())
+    }
+
+    pub fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
+
+    /// Scheduling class of following OCR requests, `Interactive` by default.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Runs OCR tasks on the worker threads of `other` instead of own workers, so that
+    /// interactive requests of one instance are scheduled ahead of bulk requests of the other.
+    /// The instances stay separate clients of the workers, so their requests don't supersede
+    /// each other. Workers are stopped once every instance using them has been dropped.
+    pub fn share_workers(&mut self, other: &KanjiTomo) {
+        self.ocr = other.ocr.clone();
+        self.client = OCRManager::register_client(&self.ocr);
+    }
+
+    /// Handle to the worker threads of this instance, see `set_workers`.
+    pub fn workers(&self) -> Workers {
+        Workers { manager: self.ocr.clone(), client: self.client.clone() }
+    }
+
+    /// Runs OCR tasks on `workers` as the same client as the instance the handle came from.
+    /// Instances are bound to the thread they were created on, so this is how a hover reader
+    /// on another thread takes over requests. An interactive request of either instance
+    /// supersedes the running interactive request of the other.
+    pub fn set_workers(&mut self, workers: Workers) {
+        self.ocr = workers.manager;
+        self.client = workers.client;
+    }
+
+    /// Finds character areas and columns from the image. Following `run_ocr` calls read
+    /// characters from this image. Images without pixels are rejected and the previous
+    /// target image is cleared.
+    pub fn set_target_image<I: Into<TargetImage>>(&mut self, image: I) -> Result<(), KanjitomoError> {
+        let image = image.into().to_rgba();
+        if image.width() == 0 || image.height() == 0 {
+            self.area_task = None;
+            return Err(KanjitomoError::EmptyImage { width: image.width(), height: image.height() });
+        }
 
+        self.area_task = Some(AreaDetector::new(AreaTask::new(image, self.parameters.clone()), self.pipeline.clone()).run());
+        Ok(())
     }
-    pub fn run_ocr(&mut self, point: Point) {
 
+    /// Reads characters starting from the character closest to `point`. Reading follows the
+    /// column and continues to the next column until `ocr_max_characters` characters are found.
+    pub fn run_ocr(&mut self, point: Point) -> Result<OCRResult, KanjitomoError> {
+        self.run_ocr_with_token(point, CancellationToken::new())
+    }
+
+    /// Same as `run_ocr`, but the request can be cancelled from another thread with `token`.
+    /// Starting an interactive request supersedes the previous interactive one on the same
+    /// workers, also when it was started on another thread, so a hover reader only waits for
+    /// the latest position. Cancelled requests return `KanjitomoError::Cancelled`.
+    pub fn run_ocr_with_token(&mut self, point: Point, token: CancellationToken) -> Result<OCRResult, KanjitomoError> {
+        let area_task = self.area_task.as_ref()
+            .ok_or_else(|| KanjitomoError::Custom("Target image must be set before running OCR!".to_owned()))?;
+        let request_id = self.ocr.begin_request(self.client.id, &token, self.priority);
+
+        let areas = area_task.get_areas(point);
+        let orientation = match areas.first().and_then(|area| area.column.upgrade()) {
+            Some(column) => column.borrow().get_orientation(),
+            None if self.parameters.vertical => Orientation::Vertical,
+            None => Orientation::Horizontal
+        };
+
+        let mut handles = vec![];
+        for (index, area) in areas.iter().enumerate() {
+            if area.punctuation {
+                continue;
+            }
+
+            let mut task = OCRTask::new(area_task.get_sub_image(area), &self.parameters);
+            task.char_index = Some(index as u32);
+            task.request_id = request_id;
+            task.cancellation = token.clone();
+            task.priority = self.priority;
+            handles.push(self.ocr.add_task(task, &self.matcher));
+        }
+
+        let mut tasks = handles.into_iter().map(TaskHandle::wait).collect::<Result<Vec<_>, _>>()?;
+        self.apply_dictionary_bias(&mut tasks);
+
+        let mut tasks: HashMap<u32, OCRTask> = tasks.into_iter()
+            .filter_map(|task| task.char_index.map(|index| (index, task)))
+            .collect();
+
+        let mut characters = vec![];
+        for (index, area) in areas.iter().enumerate() {
+            let location = Rect::from(area_task.map_to_original(area.get_rectangle()));
+
+            let character = match (area.get_punctuation(), tasks.remove(&(index as u32))) {
+                (Some(punctuation), _) => IdentifiedCharacter::new(punctuation.to_string(), location, vec![]),
+                (None, Some(task)) => {
+                    let scores = task.results.iter().map(|result| result.score).collect();
+                    IdentifiedCharacter::new(task.get_result_string(), location, scores)
+                },
+                (None, None) => IdentifiedCharacter::new(String::new(), location, vec![])
+            };
+            characters.push(character);
+        }
+
+        let mut result = OCRResult::new(characters, orientation);
+
+        for (column, start, end) in area_task.get_furigana(&areas) {
+            let handles: Vec<TaskHandle> = column.areas.iter()
+                .enumerate()
+                .map(|(index, area)| {
+                    let mut task = OCRTask::new_furigana(area_task.get_sub_image(area), &self.parameters);
+                    task.char_index = Some(index as u32);
+                    task.request_id = request_id;
+                    task.cancellation = token.clone();
+                    task.priority = self.priority;
+                    self.ocr.add_task(task, &self.matcher)
+                })
+                .collect();
+
+            let mut tasks = handles.into_iter().map(TaskHandle::wait).collect::<Result<Vec<_>, _>>()?;
+            let reading: String = tasks.iter_mut()
+                .filter_map(|task| {
+                    task.filter_kana();
+                    task.get_character()
+                })
+                .collect();
+
+            if !reading.is_empty() {
+                result.furigana.push(Furigana { reading, start, end });
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Reranks OCR candidates so that sequences found in the dictionary get their scores
+    /// multiplied by `default_dictionary_bias`. Words end at punctuation.
+    fn apply_dictionary_bias(&self, tasks: &mut [OCRTask]) {
+        if let Some(ref dictionary) = self.dictionary {
+            dictionary.apply_bias(tasks, self.parameters.default_dictionary_bias, self.parameters.index_max_characters as usize);
+        }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Orientation {
     Auto,
     Vertical,
     Horizontal
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CharacterColor {
     Auto,
     BlackOnWhite,
     WhiteOnBlack
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Method used to separate characters from the background.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinarizationMode {
+    /// Single threshold (`pixel_rgba_threshold`) for the whole image.
+    Global,
+    /// Local threshold from mean and deviation of the surrounding window, works best for
+    /// uneven lighting.
+    Sauvola,
+    /// Local threshold from mean and deviation of the surrounding window.
+    Niblack,
+    /// Otsu's threshold calculated separately for each tile.
+    Otsu
+}
+
+/// Built-in stage of the preprocessing pipeline, see `Pipeline::from_parameters`. Written to
+/// configuration files as a string, `"scale:2"` for `Scale(2)`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum PreprocessStep {
+    Unsharp,
+    /// Only run if `screentone_suppression` is set.
+    Screentone,
+    Threshold,
+    Invert,
+    Denoise,
+    /// Scales the image by the given factor with `upscale_filter`.
+    Scale(u32)
+}
+
+/// Interpolation used when small text is upscaled.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScaleFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3
+}
+
+impl From<PreprocessStep> for String {
+    fn from(step: PreprocessStep) -> Self {
+        match step {
+            PreprocessStep::Unsharp => "unsharp".to_owned(),
+            PreprocessStep::Screentone => "screentone".to_owned(),
+            PreprocessStep::Threshold => "threshold".to_owned(),
+            PreprocessStep::Invert => "invert".to_owned(),
+            PreprocessStep::Denoise => "denoise".to_owned(),
+            PreprocessStep::Scale(factor) => format!("scale:{}", factor),
+        }
+    }
+}
+
+impl TryFrom<String> for PreprocessStep {
+    type Error = String;
+
+    fn try_from(step: String) -> Result<Self, Self::Error> {
+        match step.as_str() {
+            "unsharp" => Ok(PreprocessStep::Unsharp),
+            "screentone" => Ok(PreprocessStep::Screentone),
+            "threshold" => Ok(PreprocessStep::Threshold),
+            "invert" => Ok(PreprocessStep::Invert),
+            "denoise" => Ok(PreprocessStep::Denoise),
+            _ => step.strip_prefix("scale:")
+                .and_then(|factor| factor.parse().ok())
+                .map(PreprocessStep::Scale)
+                .ok_or_else(|| format!("unknown preprocessing step `{}`", step))
+        }
+    }
+}
+
+impl From<ScaleFilter> for FilterType {
+    fn from(filter: ScaleFilter) -> Self {
+        match filter {
+            ScaleFilter::Nearest => FilterType::Nearest,
+            ScaleFilter::Triangle => FilterType::Triangle,
+            ScaleFilter::CatmullRom => FilterType::CatmullRom,
+            ScaleFilter::Gaussian => FilterType::Gaussian,
+            ScaleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type", content = "name")]
 pub enum DictionaryType {
     JapaneseDefault(String),
     JapaneseNames(String),
@@ -57,6 +377,35 @@ pub struct OCRResult {
     pub best_matching_characters: Vec<String>,
     pub characters: Vec<IdentifiedCharacter<u32>>,
     pub search_string: String,
+    /// Orientation the text was read in, either `Vertical` or `Horizontal`.
+    pub orientation: Orientation,
+    /// Readings found next to the characters.
+    pub furigana: Vec<Furigana>,
+}
+
+impl OCRResult {
+    pub(crate) fn new(characters: Vec<IdentifiedCharacter<u32>>, orientation: Orientation) -> Self {
+        let best_matching_characters: Vec<String> = characters.iter()
+            .map(|c| c.reference_characters.chars().next().map(|c| c.to_string()).unwrap_or_default())
+            .collect();
+        let search_string = best_matching_characters.concat();
+
+        Self {
+            best_matching_characters,
+            characters,
+            search_string,
+            orientation,
+            furigana: vec![]
+        }
+    }
+}
+
+/// Furigana reading of base characters `characters[start..end]` in `OCRResult`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Furigana {
+    pub reading: String,
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug)]
@@ -123,14 +472,223 @@ pub struct Rect {
     pub height: u32,
 }
 
+impl From<image::math::Rect> for Rect {
+    fn from(rect: image::math::Rect) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{PARAMETERS, Word};
+    use crate::{CancellationToken, Furigana, KanjiTomo, KanjitomoError, Orientation, Point, Word};
+    use crate::dictionary::Dictionary;
+    use crate::ocr::{OCR, OCRTask};
     use crate::parameters::Parameters;
+    use crate::preprocess::{Pipeline, PreprocessStage};
+    use crossbeam::channel::{bounded, Receiver};
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Stage that blocks the worker until it's released.
+    struct BlockingStage(Receiver<()>);
+
+    impl PreprocessStage for BlockingStage {
+        fn process(&self, image: DynamicImage) -> DynamicImage {
+            self.0.recv().ok();
+            image
+        }
+    }
+
+    /// Two characters in a column.
+    fn page() -> RgbaImage {
+        RgbaImage::from_fn(60, 80, |x, y| {
+            let character = x >= 20 && x < 40 && ((y >= 10 && y < 30) || (y >= 40 && y < 60));
+            if character { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        })
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        while !condition() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_instance_parameters() {
+        let vertical = KanjiTomo::with_parameters(Parameters { vertical: true, ..Default::default() }).unwrap();
+        let horizontal = KanjiTomo::with_parameters(Parameters { vertical: false, ..Default::default() }).unwrap();
+
+        assert!(vertical.parameters().vertical);
+        assert!(!horizontal.parameters().vertical);
+        assert_eq!(&Parameters::default(), KanjiTomo::new().parameters());
+        assert!(KanjiTomo::with_parameters(Parameters { target_size: 33, ..Default::default() }).is_err());
+    }
 
     #[test]
-    fn test_static_parameters() {
-        assert_eq!(*PARAMETERS, Parameters::default())
+    fn test_supersede_request_of_another_thread() {
+        let parameters = Parameters { ocr_threads: 1, ..Default::default() };
+        let mut first = KanjiTomo::with_parameters(parameters.clone()).unwrap();
+        first.set_target_image(page()).unwrap();
+        let manager = first.workers().manager;
+
+        // Keep the only worker busy until both requests have been queued
+        let (release, blocked) = bounded(0);
+        let mut pipeline = Pipeline::new();
+        pipeline.push(BlockingStage(blocked));
+        let blocking = Arc::new(OCR::new(Arc::new(parameters.clone()), Arc::new(pipeline)));
+        let blocker = manager.add_task(OCRTask::new(RgbaImage::new(8, 8), &parameters), &blocking);
+
+        let workers = first.workers();
+        let token = CancellationToken::new();
+        let second = {
+            let token = token.clone();
+            thread::spawn(move || {
+                let mut second = KanjiTomo::with_parameters(parameters).unwrap();
+                second.set_workers(workers);
+                second.set_target_image(page()).unwrap();
+                second.run_ocr_with_token(Point { x: 30, y: 20 }, token).map(|result| result.characters.len())
+            })
+        };
+
+        // Tasks of the second request are queued behind the blocker, the worker is released
+        // once the first request has superseded it
+        wait_for(|| manager.pending_tasks() > 1);
+        let releaser = thread::spawn(move || {
+            wait_for(|| token.is_cancelled());
+            release.send(()).unwrap();
+        });
+
+        assert!(!first.run_ocr(Point { x: 30, y: 20 }).unwrap().characters.is_empty());
+        match second.join().unwrap() {
+            Err(KanjitomoError::Cancelled) => (),
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert!(blocker.wait().is_ok());
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn test_shared_workers_keep_requests() {
+        let mut first = KanjiTomo::new();
+        let mut second = KanjiTomo::new();
+        second.share_workers(&first);
+        first.set_target_image(page()).unwrap();
+        second.set_target_image(page()).unwrap();
+
+        let (first_token, second_token) = (CancellationToken::new(), CancellationToken::new());
+        first.run_ocr_with_token(Point { x: 30, y: 20 }, first_token.clone()).unwrap();
+        second.run_ocr_with_token(Point { x: 30, y: 20 }, second_token.clone()).unwrap();
+
+        assert!(Arc::ptr_eq(&first.ocr, &second.ocr));
+        assert!(!first_token.is_cancelled());
+
+        // Next request of the same instance supersedes its previous one
+        first.run_ocr(Point { x: 30, y: 20 }).unwrap();
+        assert!(first_token.is_cancelled());
+        assert!(!second_token.is_cancelled());
+    }
+
+    #[test]
+    fn test_dictionary_rerank() {
+        let mut kanjitomo = KanjiTomo::new();
+        // Katakana ro and kanji mouth look the same, so they get the same score
+        let square = RgbaImage::from_pixel(24, 24, Rgba([0, 0, 0, 255]));
+        kanjitomo.add_reference('ロ', square.clone());
+        kanjitomo.add_reference('口', square);
+        kanjitomo.set_target_image(page()).unwrap();
+
+        assert_eq!("ロロ", kanjitomo.run_ocr(Point { x: 30, y: 20 }).unwrap().search_string);
+
+        kanjitomo.dictionary = Some(Dictionary::new(vec![
+            Word::new("口口".to_owned(), "くちぐち".to_owned(), "every mouth".to_owned(), false)
+        ]));
+
+        assert_eq!("口口", kanjitomo.run_ocr(Point { x: 30, y: 20 }).unwrap().search_string);
+    }
+
+    #[test]
+    fn test_furigana_reading() {
+        let parameters = Parameters { orientation_target: Orientation::Vertical, ..Default::default() };
+        let mut kanjitomo = KanjiTomo::with_parameters(parameters).unwrap();
+        let square = RgbaImage::from_pixel(24, 24, Rgba([0, 0, 0, 255]));
+        kanjitomo.add_reference('口', square.clone());
+        kanjitomo.add_reference('ロ', square);
+
+        // Column of four characters with three small kana next to the middle two
+        let page = RgbaImage::from_fn(160, 120, |x, y| {
+            let main = x >= 100 && x < 120 && y >= 10 && (y - 10) % 24 < 20 && y < 106;
+            let furigana = x >= 123 && x < 131 && y >= 34 && (y - 34) % 10 < 8 && y < 62;
+            if main || furigana { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        });
+        kanjitomo.set_target_image(page).unwrap();
+
+        let result = kanjitomo.run_ocr(Point { x: 110, y: 20 }).unwrap();
+
+        assert_eq!(vec![Furigana { reading: "ロロロ".to_owned(), start: 1, end: 3 }], result.furigana);
+    }
+
+    #[test]
+    fn test_white_on_black_box() {
+        let mut kanjitomo = KanjiTomo::new();
+        let cross = |x: u32, y: u32| (x >= 10 && x < 14) || (y >= 10 && y < 14);
+        let outline = |x: u32, y: u32| x < 3 || x >= 21 || y < 3 || y >= 21;
+        kanjitomo.add_reference('十', RgbaImage::from_fn(24, 24, |x, y| {
+            if cross(x, y) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+        kanjitomo.add_reference('口', RgbaImage::from_fn(24, 24, |x, y| {
+            if outline(x, y) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+
+        // White cross inside a black box on a white page
+        let page = RgbaImage::from_fn(120, 90, |x, y| {
+            let in_box = x < 75 && y < 60;
+            let character = x >= 25 && x < 49 && y >= 15 && y < 39 && cross(x - 25, y - 15);
+            if in_box && !character { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        });
+        kanjitomo.set_target_image(page).unwrap();
+
+        assert_eq!("十", kanjitomo.run_ocr(Point { x: 37, y: 27 }).unwrap().search_string);
+    }
+
+    #[test]
+    fn test_subtitles_preset() {
+        let mut kanjitomo = KanjiTomo::with_preset("subtitles").unwrap();
+        let cross = |x: u32, y: u32| (x >= 10 && x < 14) || (y >= 10 && y < 14);
+        kanjitomo.add_reference('十', RgbaImage::from_fn(24, 24, |x, y| {
+            if cross(x, y) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+        kanjitomo.add_reference('口', RgbaImage::from_fn(24, 24, |x, y| {
+            if x < 3 || x >= 21 || y < 3 || y >= 21 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+
+        // White subtitle on a dark frame
+        let frame = RgbaImage::from_fn(120, 60, |x, y| {
+            let character = x >= 40 && x < 64 && y >= 15 && y < 39 && cross(x - 40, y - 15);
+            if character { Rgba([255, 255, 255, 255]) } else { Rgba([20, 20, 20, 255]) }
+        });
+        kanjitomo.set_target_image(frame).unwrap();
+
+        assert_eq!("十", kanjitomo.run_ocr(Point { x: 52, y: 27 }).unwrap().search_string);
+        assert!(KanjiTomo::with_preset("newspaper").is_err());
+    }
+
+    #[test]
+    fn test_empty_target_image() {
+        let mut kanjitomo = KanjiTomo::new();
+        kanjitomo.set_target_image(page()).unwrap();
+
+        match kanjitomo.set_target_image(RgbaImage::new(0, 40)) {
+            Err(KanjitomoError::EmptyImage { width: 0, height: 40 }) => (),
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert!(kanjitomo.run_ocr(Point { x: 0, y: 0 }).is_err());
     }
 
     #[test]
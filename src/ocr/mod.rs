@@ -6,17 +6,128 @@ mod ocr_manager;
 mod transform;
 
 pub(crate) use ocr_result::OCRResult;
-pub(crate) use ocr_manager::OCRManager;
+pub(crate) use ocr_manager::{Client, OCRManager, TaskHandle};
+pub use ocr_manager::{CancellationToken, Priority, Workers};
+pub(crate) use ocr_task::OCRTask;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hasher, BuildHasherDefault, Hash};
 use std::fmt::Formatter;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 use serde::Serialize;
 use nalgebra::DMatrix;
-use crate::util::matrix_util::is_bit_set;
+use image::{DynamicImage, GrayImage, RgbaImage};
+use crate::util::{is_image, is_kana, make_bw, stretch_check_ratio};
+use crate::util::matrix_util::{is_bit_set, count_bits, build_mx_halo};
+use crate::error::KanjitomoError;
+use crate::parameters::Parameters;
+use crate::preprocess::Pipeline;
+use transform::{Transform, bit_matrix};
 use bit::BitIndex;
 
-pub struct OCR {
+/// Matches character images against reference characters. Workers run `run` for every task,
+/// so the matcher carries everything an instance configures: parameters, the pipeline target
+/// images are preprocessed with and the references.
+#[derive(Clone)]
+pub(crate) struct OCR {
+    parameters: Arc<Parameters>,
+    pipeline: Arc<Pipeline>,
+    references: Vec<ReferenceMatrix>,
+}
+
+impl OCR {
+    /// Maximum translation of target matrices in pixels.
+    const MAX_TRANSLATE: i32 = 2;
+    /// Maximum stretch of target matrices in pixels.
+    const MAX_STRETCH: i32 = 4;
+    /// Maximum sum of translations and stretches of a single target matrix.
+    const MAX_STEPS: i32 = 4;
+    /// Number of references scored between cancellation checks.
+    const REFERENCE_BATCH: usize = 256;
+
+    pub(crate) fn new(parameters: Arc<Parameters>, pipeline: Arc<Pipeline>) -> Self {
+        Self {
+            parameters,
+            pipeline,
+            references: vec![],
+        }
+    }
+
+    pub(crate) fn add_reference(&mut self, character: char, image: &GrayImage) {
+        self.references.push(ReferenceMatrix::new(character, image, &self.parameters));
+    }
+
+    pub(crate) fn reference_count(&self) -> usize {
+        self.references.len()
+    }
+
+    /// Preprocesses the task image and fills `task.results` with the best matching
+    /// references. Untransformed target is first compared against every reference and
+    /// `ocr_keep_results_lvl1` best are kept. These are compared against every target
+    /// transformation and `ocr_keep_results_lvl2` best are kept, sorted by score. Furigana
+    /// tasks are only compared against kana. Cancelled tasks stop between reference batches
+    /// and are left without results.
+    pub(crate) fn run(&self, task: &mut OCRTask) {
+        let image = DynamicImage::ImageLuma8(task.image.clone());
+        task.image = self.pipeline.run(image).to_luma();
+
+        let targets = Transform::new(task, &self.parameters).run(Self::MAX_TRANSLATE, Self::MAX_STRETCH, Self::MAX_STEPS);
+        let identity = match targets.iter().find(|target| target.transform == Transformation::default()) {
+            Some(identity) => identity,
+            None => return
+        };
+
+        let mut candidates: Vec<(u32, &ReferenceMatrix)> = vec![];
+        for batch in self.references.chunks(Self::REFERENCE_BATCH) {
+            if task.is_cancelled() {
+                log::trace!("scoring of request {} cancelled", task.request_id);
+                return;
+            }
+
+            candidates.extend(batch.iter()
+                .filter(|reference| !task.furigana || is_kana(reference.character))
+                .map(|reference| (OCRResult::score(identity, reference, &self.parameters), reference)));
+        }
+        candidates.sort_by(|a, b| b.0.cmp(&a.0));
+        candidates.truncate(self.parameters.ocr_keep_results_lvl1 as usize);
+
+        let mut results = vec![];
+        for (_, reference) in candidates {
+            // Every kept reference is compared against all targets, check between them
+            if task.is_cancelled() {
+                log::trace!("comparison of request {} cancelled", task.request_id);
+                return;
+            }
+
+            let best = targets.iter().max_by_key(|target| OCRResult::score(target, reference, &self.parameters));
+            results.extend(best.map(|target| OCRResult::compare(target, reference, &self.parameters)));
+        }
+        task.results = results;
+        task.sort_results();
+        task.results.truncate(self.parameters.ocr_keep_results_lvl2 as usize);
+    }
+}
+
+/// Reads reference character images from `dir`. File name without the extension is the
+/// character, `漢.png` is the reference of 漢. Other images are skipped.
+pub fn read_references<P: AsRef<Path>>(dir: P) -> Result<Vec<(char, RgbaImage)>, KanjitomoError> {
+    let mut paths: Vec<_> = fs::read_dir(dir.as_ref())
+        .map_err(KanjitomoError::IOError)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_image(path))
+        .collect();
+    paths.sort();
+
+    let mut references = vec![];
+    for path in paths {
+        let mut stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().chars();
+        if let (Some(character), None) = (stem.next(), stem.next()) {
+            references.push((character, image::open(&path).map_err(KanjitomoError::OCRError)?.to_rgba()));
+        }
+    }
 
+    Ok(references)
 }
 
 #[derive(Default, Clone, Debug)]
@@ -49,15 +160,51 @@ impl TargetMatrix {
 #[derive(Default, Clone, Debug)]
 pub(crate) struct ReferenceMatrix {
     pub(crate) character: char,
-    matrix: Vec<u8>,
+    matrix: [u32; 32],
     pixels: u32,
-    halo: Vec<Vec<u8>>,
+    halo: Vec<[u32; 32]>,
     score_modifier: f32,
     font_name: String,
     components: Vec<Component>,
     transformations: Vec<Transformation>,
 }
 
+impl ReferenceMatrix {
+    /// Builds reference from an image of the character. Margins around the character are
+    /// cropped, then the image is scaled like target images.
+    pub(crate) fn new(character: char, image: &GrayImage, parameters: &Parameters) -> Self {
+        let image = crop_to_content(image, parameters.pixel_rgba_threshold);
+        let resized = stretch_check_ratio(&image, parameters.target_size, parameters.target_size);
+        let matrix = bit_matrix(&resized, parameters.target_size, parameters.target_size, parameters.pixel_rgba_threshold);
+
+        Self {
+            character,
+            matrix,
+            pixels: count_bits(&matrix),
+            halo: build_mx_halo(&matrix, parameters.ocr_halo_size),
+            ..Default::default()
+        }
+    }
+}
+
+/// Crops image to the bounding box of its black pixels.
+fn crop_to_content(image: &GrayImage, black_threshold: u8) -> GrayImage {
+    let bw = make_bw(image, black_threshold);
+    let black: Vec<(u32, u32)> = bw.enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[0] == 0)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+
+    let (min_x, max_x) = (black.iter().map(|p| p.0).min(), black.iter().map(|p| p.0).max());
+    let (min_y, max_y) = (black.iter().map(|p| p.1).min(), black.iter().map(|p| p.1).max());
+    match (min_x, max_x, min_y, max_y) {
+        (Some(min_x), Some(max_x), Some(min_y), Some(max_y)) => {
+            image::imageops::crop_imm(image, min_x, min_y, max_x - min_x + 1, max_y - min_y + 1).to_image()
+        },
+        _ => image.clone()
+    }
+}
+
 pub(crate) struct ReferenceMatrixCacheLoader {
     cache: Option<ReferenceMatrixCache>,
 }
@@ -241,3 +388,72 @@ impl Transformation {
             && self.vertical_stretch == v_s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, ImageBuffer};
+
+    /// 24x24 character drawn from 3 pixel wide strokes, `(x, y, width, height)` in stroke units.
+    fn glyph(strokes: &[(u32, u32, u32, u32)]) -> GrayImage {
+        ImageBuffer::from_fn(24, 24, |x, y| {
+            let black = strokes.iter().any(|&(sx, sy, width, height)| {
+                x >= sx * 3 && x < (sx + width) * 3 && y >= sy * 3 && y < (sy + height) * 3
+            });
+            if black { Luma([0]) } else { Luma([255]) }
+        })
+    }
+
+    #[test]
+    fn test_run() {
+        let parameters = Arc::new(Parameters::default());
+        let mut ocr = OCR::new(parameters.clone(), Arc::new(Pipeline::new()));
+        ocr.add_reference('口', &glyph(&[(0, 0, 8, 1), (0, 7, 8, 1), (0, 0, 1, 8), (7, 0, 1, 8)]));
+        ocr.add_reference('十', &glyph(&[(0, 3, 8, 2), (3, 0, 2, 8)]));
+        ocr.add_reference('一', &glyph(&[(0, 3, 8, 2)]));
+        ocr.add_reference('あ', &glyph(&[(0, 0, 8, 8)]));
+
+        // Thinner cross that's a bit off center
+        let target = ImageBuffer::from_fn(24, 24, |x, y| {
+            if (y >= 10 && y < 15) || (x >= 11 && x < 16) { Luma([0]) } else { Luma([255]) }
+        });
+        let mut task = OCRTask::new(DynamicImage::ImageLuma8(target.clone()).to_rgba(), &parameters);
+        ocr.run(&mut task);
+
+        assert_eq!(4, task.results.len());
+        assert_eq!(Some('十'), task.get_character());
+        assert!(task.results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+
+        let mut furigana = OCRTask::new_furigana(DynamicImage::ImageLuma8(target).to_rgba(), &parameters);
+        ocr.run(&mut furigana);
+        assert_eq!("あ", furigana.get_result_string());
+    }
+
+    #[test]
+    fn test_cancelled_run() {
+        let parameters = Arc::new(Parameters::default());
+        let mut ocr = OCR::new(parameters.clone(), Arc::new(Pipeline::new()));
+        ocr.add_reference('十', &glyph(&[(0, 3, 8, 2), (3, 0, 2, 8)]));
+
+        let mut task = OCRTask::new(DynamicImage::ImageLuma8(glyph(&[(0, 3, 8, 2), (3, 0, 2, 8)])).to_rgba(), &parameters);
+        task.cancellation.cancel();
+        ocr.run(&mut task);
+
+        assert!(task.results.is_empty());
+
+        // Cancel while the references are scored, scoring this many takes far longer than
+        // building the targets
+        let reference = ocr.references[0].clone();
+        ocr.references = vec![reference; 100_000];
+        let mut task = OCRTask::new(DynamicImage::ImageLuma8(glyph(&[(0, 3, 8, 2), (3, 0, 2, 8)])).to_rgba(), &parameters);
+        let token = task.cancellation.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            token.cancel();
+        });
+        ocr.run(&mut task);
+        canceller.join().unwrap();
+
+        assert!(task.results.is_empty());
+    }
+}
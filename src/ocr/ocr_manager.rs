@@ -1,130 +1,538 @@
-use std::thread::{Thread, JoinHandle, self};
+use std::thread::{self, JoinHandle};
 use crate::ocr::ocr_task::OCRTask;
-use crate::area::AreaTask;
-use std::iter;
-use std::sync::{Arc, Mutex};
+use crate::ocr::OCR;
+use std::sync::{Arc, Mutex, Condvar};
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use crate::error::KanjitomoError;
-use std::sync::atomic::{AtomicBool, Ordering, AtomicU32};
-use rayon::{ThreadPool, ThreadPoolBuilder};
-use crossbeam::channel::{Sender, Receiver, unbounded};
-use crate::util::{sharpen_image, make_bw};
-use crate::PARAMETERS;
+use crossbeam::channel::{Sender, Receiver, bounded};
+use crate::preprocess::Pipeline;
+use crate::parameters::Parameters;
 
+/// Flag shared between the caller and the tasks of an OCR request. Cancelled tasks that are
+/// still queued are dropped and running ones stop between transformation and reference batches.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Scheduling class of an OCR request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Priority {
+    /// Single point requests a user is waiting for. Run before queued bulk tasks, and a new
+    /// interactive request supersedes the previous one.
+    Interactive,
+    /// Background work such as reading whole pages.
+    Bulk,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Interactive
+    }
+}
+
+/// Task waiting for a worker, with the matcher it's run with and the channel its result is
+/// sent to.
+struct Job {
+    task: OCRTask,
+    ocr: Arc<OCR>,
+    result: Sender<OCRTask>,
+}
+
+/// Queue with a lane for each priority. Interactive tasks go first, but after
+/// `MAX_INTERACTIVE_STREAK` interactive tasks in a row a waiting bulk task is taken so that
+/// bulk work isn't starved by a steady stream of interactive requests.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    interactive: VecDeque<Job>,
+    bulk: VecDeque<Job>,
+    interactive_streak: usize,
+    closed: bool,
+}
+
+impl Queue {
+    const MAX_INTERACTIVE_STREAK: usize = 4;
+
+    /// Returns the job back if the queue has been closed.
+    fn push(&self, job: Job) -> Result<(), Job> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return Err(job);
+        }
+
+        match job.task.priority {
+            Priority::Interactive => state.interactive.push_back(job),
+            Priority::Bulk => state.bulk.push_back(job),
+        }
+        self.available.notify_one();
+
+        Ok(())
+    }
+
+    /// Blocks until a job is available. Returns `None` once the queue is closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        loop {
+            let take_bulk = !state.bulk.is_empty()
+                && (state.interactive.is_empty() || state.interactive_streak >= Self::MAX_INTERACTIVE_STREAK);
+
+            if take_bulk {
+                state.interactive_streak = 0;
+                return state.bulk.pop_front();
+            }
+            if let Some(job) = state.interactive.pop_front() {
+                state.interactive_streak += 1;
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+
+            state = self.available.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed
+    }
+
+    /// Stops accepting jobs. Queued jobs are still handed out.
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.available.notify_all();
+    }
+}
+
+/// Number of submitted tasks that haven't been finished yet.
+#[derive(Debug, Default)]
+struct Pending {
+    count: AtomicUsize,
+    lock: Mutex<()>,
+    idle: Condvar,
+}
+
+/// Marks a task finished when dropped, also when the worker panics.
+struct PendingGuard(Arc<Pending>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _lock = self.0.lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// Result of a task submitted to `OCRManager`.
+#[derive(Debug)]
+pub(crate) struct TaskHandle {
+    result: Receiver<OCRTask>,
+    cancellation: CancellationToken,
+}
+
+impl TaskHandle {
+    /// Blocks until the task has been processed.
+    pub(crate) fn wait(self) -> Result<OCRTask, KanjitomoError> {
+        let cancellation = self.cancellation;
+        match self.result.recv() {
+            Ok(task) if task.is_cancelled() => Err(KanjitomoError::Cancelled),
+            Ok(task) => Ok(task),
+            Err(_) if cancellation.is_cancelled() => Err(KanjitomoError::Cancelled),
+            Err(_) => Err(KanjitomoError::TaskAborted)
+        }
+    }
+}
+
+/// Worker threads of a `KanjiTomo` instance and the client the instance's requests are made
+/// as. Unlike the instance, the handle can be sent to other threads, where
+/// `KanjiTomo::set_workers` makes another instance run OCR on the same workers as the same
+/// client.
+#[derive(Debug, Clone)]
+pub struct Workers {
+    pub(crate) manager: Arc<OCRManager>,
+    pub(crate) client: Arc<Client>,
+}
+
+/// Registration of a client on the workers. Instances and `Workers` handles of the same
+/// client share it, the client is unregistered once the last of them is dropped.
 #[derive(Debug)]
+pub(crate) struct Client {
+    pub(crate) id: u64,
+    manager: Arc<OCRManager>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.manager.unregister_client(self.id);
+    }
+}
+
+/// Runs OCR tasks on worker threads. Workers are started when the first task is added, so a
+/// manager that is replaced by a shared one costs nothing. They run until the manager is
+/// shut down or dropped, which waits for queued tasks to finish and joins every worker. The
+/// manager can be shared by several `KanjiTomo` instances, each task carries the matcher of
+/// its instance. A task that panics is reported as aborted and the worker moves on to the
+/// next one.
 pub(crate) struct OCRManager {
-    thread_pool: ThreadPool,
-    pending_tasks: Sender<OCRTask>,
-    results: Receiver<OCRTask>,
-    task_count: AtomicU32,
-    stop_flag: Arc<AtomicBool>
+    queue: Arc<Queue>,
+    threads: usize,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    pending: Arc<Pending>,
+    last_request: AtomicU64,
+    last_client: AtomicU64,
+    /// Token of the latest interactive request of each client, cancelled when the client's
+    /// next one begins.
+    current_requests: Mutex<HashMap<u64, CancellationToken>>,
 }
 
 impl OCRManager {
-    pub(crate) fn new() -> Self {
-        let mut thread_pool = ThreadPoolBuilder::new()
-            .num_threads(PARAMETERS.ocr_threads)
-            .thread_name(|idx| {
-                format!("OCRTask ({})", idx)
+    pub(crate) fn new(threads: usize) -> Self {
+        Self {
+            queue: Arc::new(Queue::default()),
+            threads,
+            workers: Mutex::new(vec![]),
+            pending: Arc::new(Pending::default()),
+            last_request: AtomicU64::new(0),
+            last_client: AtomicU64::new(0),
+            current_requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spawns the worker threads unless they are already running or the manager has been
+    /// shut down.
+    fn start_workers(&self) {
+        let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+        if !workers.is_empty() || self.queue.is_closed() {
+            return;
+        }
+
+        *workers = (0..self.threads)
+            .map(|index| {
+                let queue = self.queue.clone();
+                let pending = self.pending.clone();
+
+                thread::Builder::new()
+                    .name(format!("OCRTask ({})", index))
+                    .spawn(move || Self::work(queue, pending))
+                    .expect("Failed to spawn OCR worker thread!")
             })
-            .build()
-            .unwrap();
-        let (s, r) = unbounded();
-        let (res_s, res_r) = unbounded();
-
-        let stop_flag = Arc::new(AtomicBool::new(false));
-
-        let mut mgr = Self {
-            thread_pool,
-            pending_tasks: s,
-            results: res_r,
-            task_count: Default::default(),
-            stop_flag
-        };
-        mgr.install_threads(res_s, r);
-
-        mgr
-    }
-
-    fn install_threads(&mut self, res_s: Sender<OCRTask>, r: Receiver<OCRTask>) {
-        let stop_flag = self.stop_flag.clone();
-        self.thread_pool.install(move || {
-            for i in 0..PARAMETERS.ocr_threads {
-                let res_s = res_s.clone();
-                let r = r.clone();
-                let stop_flag = stop_flag.clone();
-
-                rayon::spawn(move || {
-                    log::trace!("thread id is {:?} name is {:?}", thread::current().id(), thread::current().name());
-                    loop {
-                        if stop_flag.load(Ordering::Relaxed) {
-                            log::trace!("{} got a shutdown request.", thread::current().name().unwrap());
-                            break
-                        }
-                        if let Ok(mut task) = r.recv() {
-                            log::trace!("got task in {:?}", thread::current().name());
-                            let image = &task.image;
-                            let sharpened = sharpen_image(image, None, None);
-                            let b_w = make_bw(&sharpened, None);
-                            task.image = b_w;
-                            res_s.send(task).unwrap();
-                            log::trace!("Sent result to receiver from {:?}", thread::current().name());
-                        }
-                    }
-                })
+            .collect();
+    }
+
+    fn work(queue: Arc<Queue>, pending: Arc<Pending>) {
+        // Queue returns nothing once it's closed and empty
+        while let Some(Job { mut task, ocr, result }) = queue.pop() {
+            let _guard = PendingGuard(pending.clone());
+            if task.is_cancelled() {
+                log::trace!("dropping task of superseded request {}", task.request_id);
+                continue;
             }
+            log::trace!("got task in {:?}", thread::current().name());
+
+            let request_id = task.request_id;
+            let finished = panic::catch_unwind(AssertUnwindSafe(move || {
+                ocr.run(&mut task);
+                task
+            }));
+
+            match finished {
+                // Nobody is waiting for the result if the handle was dropped
+                Ok(task) => { result.send(task).ok(); },
+                // Dropping the sender reports the task as aborted
+                Err(_) => log::error!("OCR task of request {} panicked", request_id)
+            }
+        }
+
+        log::trace!("{:?} shut down", thread::current().name());
+    }
+
+    /// New client of the workers. Every `KanjiTomo` instance is a client of its own, so
+    /// instances sharing workers don't supersede each other's requests.
+    pub(crate) fn register_client(manager: &Arc<Self>) -> Arc<Client> {
+        Arc::new(Client {
+            id: manager.last_client.fetch_add(1, Ordering::SeqCst) + 1,
+            manager: manager.clone(),
         })
     }
 
-    pub(crate) fn add_task(&mut self, task: OCRTask) {
-        self.pending_tasks.send(task).unwrap();
-        let mut task_count = self.task_count.load(Ordering::SeqCst);
-        task_count += 1;
-        self.task_count.store(task_count, Ordering::SeqCst);
+    /// Forgets the latest request of a dropped client.
+    fn unregister_client(&self, client: u64) {
+        self.current_requests.lock().unwrap_or_else(|e| e.into_inner()).remove(&client);
+    }
+
+    /// Starts a new request of `client`. An interactive request supersedes the previous
+    /// interactive one of the same client, whose queued tasks are dropped and running tasks
+    /// stopped. Bulk requests are only stopped through their own token. Tasks of the request
+    /// must be given the returned id, `token` and `priority`.
+    pub(crate) fn begin_request(&self, client: u64, token: &CancellationToken, priority: Priority) -> u64 {
+        if priority == Priority::Interactive {
+            let mut current = self.current_requests.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(previous) = current.insert(client, token.clone()) {
+                previous.cancel();
+            }
+        }
+
+        self.last_request.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Queues task for the workers, it's run with `ocr`. Result is delivered through the
+    /// returned handle.
+    pub(crate) fn add_task(&self, task: OCRTask, ocr: &Arc<OCR>) -> TaskHandle {
+        self.start_workers();
+        let (result, receiver) = bounded(1);
+        let cancellation = task.cancellation.clone();
+        self.pending.count.fetch_add(1, Ordering::SeqCst);
+
+        let job = Job { task, ocr: ocr.clone(), result };
+        if self.queue.push(job).is_err() {
+            // Dropping the guard takes the task back out of the count, the handle reports it
+            // as aborted
+            drop(PendingGuard(self.pending.clone()));
+        }
+
+        TaskHandle { result: receiver, cancellation }
+    }
+
+    /// Number of running worker threads.
+    pub(crate) fn worker_count(&self) -> usize {
+        self.workers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Number of tasks that have been queued but not finished.
+    pub(crate) fn pending_tasks(&self) -> usize {
+        self.pending.count.load(Ordering::SeqCst)
     }
 
-    pub(crate) fn stop_threads(&mut self) {
-        self.stop_flag.store(true, Ordering::SeqCst)
+    /// Blocks until every queued task has been finished.
+    pub(crate) fn wait_until_done(&self) {
+        let mut lock = self.pending.lock.lock().unwrap_or_else(|e| e.into_inner());
+        while self.pending.count.load(Ordering::SeqCst) > 0 {
+            lock = self.pending.idle.wait(lock).unwrap_or_else(|e| e.into_inner());
+        }
     }
 
-    pub(crate) fn wait_until_done(&mut self) {
-        while self.task_count.load(Ordering::SeqCst) > 0 {
-            if let Ok(task) = self.results.recv() {
-                let mut task_count = self.task_count.load(Ordering::SeqCst);
-                task_count -= 1;
-                self.task_count.store(task_count, Ordering::SeqCst);
+    /// Stops accepting tasks, lets workers finish the queued ones and joins them. Tasks added
+    /// after shutdown are aborted.
+    pub(crate) fn shutdown(&mut self) {
+        let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+        self.queue.close();
+
+        for worker in workers.drain(..) {
+            if worker.join().is_err() {
+                log::error!("OCR worker thread panicked");
             }
         }
     }
 }
 
+impl std::fmt::Debug for OCRManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OCRManager")
+            .field("workers", &self.worker_count())
+            .field("pending", &self.pending_tasks())
+            .finish()
+    }
+}
+
+impl Drop for OCRManager {
+    fn drop(&mut self) {
+        self.shutdown()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
-    use image::{ImageBuffer, open};
-    use crate::util::tests::PATH;
+    use crate::preprocess::PreprocessStage;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    fn ocr() -> Arc<OCR> {
+        let parameters = Arc::new(Parameters::default());
+        let pipeline = Pipeline::for_ocr(&parameters);
+        Arc::new(OCR::new(parameters, Arc::new(pipeline)))
+    }
+
+    fn job(index: u32, priority: Priority) -> Job {
+        let mut task = OCRTask::new(RgbaImage::new(1, 1), &Parameters::default());
+        task.char_index = Some(index);
+        task.priority = priority;
+
+        Job { task, ocr: ocr(), result: bounded(1).0 }
+    }
 
     #[test]
-    fn threads_test() {
+    fn test_results() {
         pretty_env_logger::try_init().unwrap_or(());
-        let mut mgr = Arc::new(Mutex::new(OCRManager::new()));
-        {
-            let mut mgr = mgr.clone();
-            rayon::spawn(move || {
-                for i in 0..3 {
-                    let mut mgr = mgr.lock().unwrap();
-                    mgr.add_task(OCRTask::new(ImageBuffer::new(32, 32)));
-                }
-                drop(mgr)
+        let manager = OCRManager::new(3);
+        let parameters = Parameters::default();
+        let ocr = ocr();
+
+        let handles: Vec<TaskHandle> = (0..10)
+            .map(|index| {
+                let image = RgbaImage::from_fn(32, 32, |x, _| if x < 16 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+                let mut task = OCRTask::new(image, &parameters);
+                task.char_index = Some(index);
+                manager.add_task(task, &ocr)
             })
+            .collect();
+
+        for (index, handle) in handles.into_iter().enumerate() {
+            let task = handle.wait().unwrap();
+            assert_eq!(Some(index as u32), task.char_index);
+            assert_eq!(0, task.image.get_pixel(4, 4).0[0]);
+            assert_eq!(255, task.image.get_pixel(28, 4).0[0]);
         }
 
-        let res = thread::spawn(move || {
-            let mut mgr = mgr.lock().unwrap();
-            mgr.wait_until_done();
-            mgr.stop_threads();
-        }).join().unwrap();
-        log::trace!("Finished processing.");
-        thread::sleep(Duration::from_micros(1000));
+        manager.wait_until_done();
+        assert_eq!(0, manager.pending_tasks());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cancellation() {
+        let manager = Arc::new(OCRManager::new(3));
+        let (client, other_client) = (OCRManager::register_client(&manager), OCRManager::register_client(&manager));
+        let first = CancellationToken::new();
+        let second = CancellationToken::new();
+        let bulk = CancellationToken::new();
+        let other = CancellationToken::new();
+
+        assert_eq!(1, manager.begin_request(client.id, &first, Priority::Interactive));
+        assert_eq!(2, manager.begin_request(other_client.id, &other, Priority::Interactive));
+        assert_eq!(3, manager.begin_request(client.id, &bulk, Priority::Bulk));
+        assert_eq!(4, manager.begin_request(client.id, &second, Priority::Interactive));
+        assert!(first.is_cancelled());
+        assert!(!bulk.is_cancelled());
+        assert!(!second.is_cancelled());
+        // Requests of other clients aren't superseded
+        assert!(!other.is_cancelled());
+
+        let mut task = OCRTask::new(RgbaImage::new(32, 32), &Parameters::default());
+        task.request_id = 1;
+        task.cancellation = first;
+        match manager.add_task(task, &ocr()).wait() {
+            Err(KanjitomoError::Cancelled) => (),
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        manager.wait_until_done();
+        assert_eq!(0, manager.pending_tasks());
+
+        // Dropped clients are forgotten
+        drop((client, other_client));
+        assert!(manager.current_requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_colored_text() {
+        let manager = OCRManager::new(1);
+        let parameters = Arc::new(Parameters {
+            fixed_black_level: true,
+            fixed_black_level_red: 255,
+            fixed_black_level_green: 220,
+            fixed_black_level_blue: 0,
+            ..Default::default()
+        });
+        let glyph = |text: Rgba<u8>, background: Rgba<u8>| RgbaImage::from_fn(24, 24, |x, y| {
+            if (y >= 9 && y < 15) || (x >= 9 && x < 15) { text } else { background }
+        });
+
+        let mut ocr = OCR::new(parameters.clone(), Arc::new(Pipeline::for_ocr(&parameters)));
+        let reference = glyph(Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        ocr.add_reference('十', &DynamicImage::ImageRgba8(reference).to_luma());
+
+        // Yellow text on light blue background has about the same luminance
+        let task = OCRTask::new(glyph(Rgba([255, 220, 0, 255]), Rgba([150, 220, 255, 255])), &parameters);
+        let task = manager.add_task(task, &Arc::new(ocr)).wait().unwrap();
+
+        assert_eq!(0, task.image.get_pixel(12, 2).0[0]);
+        assert_eq!(255, task.image.get_pixel(2, 2).0[0]);
+        assert_eq!(Some('十'), task.get_character());
+    }
+
+    struct PanickingStage;
+
+    impl PreprocessStage for PanickingStage {
+        fn process(&self, _image: DynamicImage) -> DynamicImage {
+            panic!("broken stage")
+        }
+    }
+
+    #[test]
+    fn test_panicking_task() {
+        let manager = OCRManager::new(1);
+        let mut pipeline = Pipeline::new();
+        pipeline.push(PanickingStage);
+        let broken = Arc::new(OCR::new(Arc::new(Parameters::default()), Arc::new(pipeline)));
+
+        let task = OCRTask::new(RgbaImage::new(32, 32), &Parameters::default());
+        match manager.add_task(task, &broken).wait() {
+            Err(KanjitomoError::TaskAborted) => (),
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        // Worker survives the panic
+        let task = OCRTask::new(RgbaImage::new(32, 32), &Parameters::default());
+        assert!(manager.add_task(task, &ocr()).wait().is_ok());
+        manager.wait_until_done();
+        assert_eq!(0, manager.pending_tasks());
+    }
+
+    #[test]
+    fn test_priority() {
+        let queue = Queue::default();
+        for index in 0..2 {
+            queue.push(job(index, Priority::Bulk)).ok().unwrap();
+        }
+        for index in 2..12 {
+            queue.push(job(index, Priority::Interactive)).ok().unwrap();
+        }
+        queue.close();
+
+        let order: Vec<u32> = std::iter::from_fn(|| queue.pop())
+            .map(|job| job.task.char_index.unwrap())
+            .collect();
+
+        // Interactive tasks go first, but every fifth task is bulk while bulk tasks wait
+        assert_eq!(vec![2, 3, 4, 5, 0, 6, 7, 8, 9, 1, 10, 11], order);
+        assert!(queue.push(job(12, Priority::Interactive)).is_err());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut manager = OCRManager::new(3);
+        // Workers are started by the first task
+        assert_eq!(0, manager.worker_count());
+        let handle = manager.add_task(OCRTask::new(RgbaImage::new(32, 32), &Parameters::default()), &ocr());
+
+        manager.shutdown();
+
+        // Queued tasks are finished before workers stop
+        assert!(handle.wait().is_ok());
+        assert_eq!(0, manager.worker_count());
+        assert!(manager.add_task(OCRTask::new(RgbaImage::new(32, 32), &Parameters::default()), &ocr()).wait().is_err());
+        assert_eq!(0, manager.pending_tasks());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::ocr::{ReferenceMatrix, TargetMatrix};
+use crate::parameters::Parameters;
+use crate::util::matrix_util::count_bits;
 
 #[derive(Default, Clone, Debug)]
 pub(crate) struct OCRResult {
@@ -23,7 +25,115 @@ impl OCRResult {
         }
     }
 
+    /// Compares target against reference, see `PixelCounts`.
+    pub(crate) fn compare(target: &TargetMatrix, reference: &ReferenceMatrix, parameters: &Parameters) -> Self {
+        let counts = PixelCounts::new(target, reference);
+        let score = counts.score(parameters);
+
+        Self {
+            target: target.clone(),
+            reference: reference.clone(),
+            black_pixels: counts.black,
+            white_pixels: counts.white(),
+            target_halo_pixels: counts.target_halo.iter().sum(),
+            reference_halo_pixels: counts.reference_halo.iter().sum(),
+            score,
+            avg_score: score as f32 / reference.pixels.max(1) as f32,
+            refined_alignment: false,
+        }
+    }
+
+    /// Score of target against reference without building the result.
+    pub(crate) fn score(target: &TargetMatrix, reference: &ReferenceMatrix, parameters: &Parameters) -> u32 {
+        PixelCounts::new(target, reference).score(parameters)
+    }
+
     pub(crate) fn get_character(&self) -> char {
         self.reference.character
     }
+
+    pub(crate) fn apply_bias(&mut self, bias: f32) {
+        self.score = (self.score as f32 * bias).round() as u32;
+        self.avg_score *= bias;
+    }
+}
+
+/// Pixels where target and reference agree and where they miss each other. Reference pixels
+/// that aren't in the target are counted by the target halo layer they fall in and target
+/// pixels that aren't in the reference by the reference halo layer. Pixels beyond the last
+/// layer are white pixels.
+struct PixelCounts {
+    black: u32,
+    target_halo: Vec<u32>,
+    reference_halo: Vec<u32>,
+    /// Reference pixels outside the target halo.
+    reference_white: u32,
+    /// Target pixels outside the reference halo.
+    target_white: u32,
+}
+
+impl PixelCounts {
+    fn new(target: &TargetMatrix, reference: &ReferenceMatrix) -> Self {
+        let (black, _) = count_overlap(&target.matrix, &reference.matrix);
+        let (target_halo, reference_white) = count_layers(&reference.matrix, &target.matrix, &target.halo);
+        let (reference_halo, target_white) = count_layers(&target.matrix, &reference.matrix, &reference.halo);
+
+        Self {
+            black,
+            target_halo,
+            reference_halo,
+            reference_white,
+            target_white,
+        }
+    }
+
+    fn white(&self) -> u32 {
+        self.reference_white + self.target_white
+    }
+
+    /// `ocr_base_score` plus `ocr_black_pixel_score` for every common pixel, and halo scores
+    /// for every missed pixel. White pixels get the score of the last halo layer.
+    fn score(&self, parameters: &Parameters) -> u32 {
+        let layer_scores = |counts: &[u32], scores: &[f32]| -> f32 {
+            counts.iter().zip(scores.iter()).map(|(&count, &score)| count as f32 * score).sum()
+        };
+        let last_target_score = parameters.ocr_target_halo_scores.last().cloned().unwrap_or(0.0);
+        let last_reference_score = parameters.ocr_reference_halo_scores.last().cloned().unwrap_or(0.0);
+
+        let score = parameters.ocr_base_score
+            + self.black as f32 * parameters.ocr_black_pixel_score
+            + layer_scores(&self.target_halo, &parameters.ocr_target_halo_scores)
+            + layer_scores(&self.reference_halo, &parameters.ocr_reference_halo_scores)
+            + self.reference_white as f32 * last_target_score
+            + self.target_white as f32 * last_reference_score;
+
+        score.max(0.0).round() as u32
+    }
+}
+
+/// Number of `pixels` that are set in `mx`, and the mask of the rest.
+fn count_overlap(pixels: &[u32; 32], mx: &[u32; 32]) -> (u32, [u32; 32]) {
+    let mut overlap = [0u32; 32];
+    let mut rest = [0u32; 32];
+    for y in 0..32 {
+        overlap[y] = pixels[y] & mx[y];
+        rest[y] = pixels[y] & !mx[y];
+    }
+
+    (count_bits(&overlap), rest)
+}
+
+/// Counts `pixels` that aren't in `mx` by the halo layer of `mx` they fall in. Returns the
+/// counts of each layer and the number of pixels outside every layer.
+fn count_layers(pixels: &[u32; 32], mx: &[u32; 32], halo: &[[u32; 32]]) -> (Vec<u32>, u32) {
+    let (_, mut rest) = count_overlap(pixels, mx);
+    let mut counts = Vec::with_capacity(halo.len());
+
+    for layer in halo {
+        let (count, outside) = count_overlap(&rest, layer);
+        counts.push(count);
+        rest = outside;
+    }
+
+    (counts, count_bits(&rest))
 }
@@ -1,26 +1,67 @@
 use image::{GrayImage, RgbaImage, GenericImage, Pixel, DynamicImage};
 use super::OCRResult;
+use crate::util::{is_kana, make_bw_fixed};
+use crate::TargetImage;
+use crate::parameters::Parameters;
+use super::{CancellationToken, Priority};
 
 #[derive(Debug, Clone)]
 pub(crate) struct OCRTask
 {
     pub(crate) image: GrayImage,
     pub(crate) char_index: Option<u32>,
-    results: Vec<OCRResult>,
+    pub(crate) results: Vec<OCRResult>,
+    /// Furigana tasks are matched against kana only.
+    pub(crate) furigana: bool,
+    /// Request the task belongs to, see `OCRManager::begin_request`.
+    pub(crate) request_id: u64,
+    pub(crate) cancellation: CancellationToken,
+    pub(crate) priority: Priority,
     column_changed: bool,
 }
 
 impl OCRTask
 {
-    pub(crate) fn new(image: RgbaImage) -> Self {
+    pub(crate) fn new<I: Into<TargetImage>>(image: I, parameters: &Parameters) -> Self {
+        let image = image.into().to_rgba();
+
+        // Color is lost in grayscale conversion, so colored text is binarized right away
+        let image = if parameters.fixed_black_level {
+            make_bw_fixed(&image, parameters.fixed_black_level_color(), parameters.fixed_black_level_range)
+        } else {
+            DynamicImage::ImageRgba8(image).to_luma()
+        };
+
         Self {
-            image: DynamicImage::ImageRgba8(image).to_luma(),
+            image,
             char_index: None,
             results: vec![],
+            furigana: false,
+            request_id: 0,
+            cancellation: CancellationToken::new(),
+            priority: Priority::Interactive,
             column_changed: false
         }
     }
 
+    pub(crate) fn new_furigana<I: Into<TargetImage>>(image: I, parameters: &Parameters) -> Self {
+        Self {
+            furigana: true,
+            ..Self::new(image, parameters)
+        }
+    }
+
+    /// Drops results that can't appear in furigana.
+    pub(crate) fn filter_kana(&mut self) {
+        if self.furigana {
+            self.results.retain(|r| is_kana(r.get_character()))
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
     pub(crate) fn get_character(&self) -> Option<char> {
         if self.results.len() > 0 {
             Some(self.results[0].get_character())
@@ -29,6 +70,10 @@ impl OCRTask
         }
     }
 
+    pub(crate) fn sort_results(&mut self) {
+        self.results.sort_by(|a, b| b.score.cmp(&a.score))
+    }
+
     pub(crate) fn get_result_string(&self) -> String {
         let mut string = String::new();
 
@@ -1,44 +1,52 @@
 use crate::ocr::ocr_task::OCRTask;
 use std::collections::HashMap;
 use crate::ocr::{Transformation, TargetMatrix};
-use crate::util::{sharpen_image, stretch, make_bw, build_bit_mx_from_32_image, create_square_image, stretch_check_ratio};
-use image::{RgbaImage, GenericImage, Pixel, ImageBuffer, GenericImageView, Rgba, FromColor, GrayImage};
-use crate::PARAMETERS;
+use crate::util::{stretch, make_bw, build_bit_mx_from_32_image, create_square_image, stretch_check_ratio};
+use image::GrayImage;
+use crate::parameters::Parameters;
 use crate::util::matrix_util::{move_matrix, count_bits, build_mx_halo};
-use imageproc::geometric_transformations::translate;
-use image::buffer::ConvertBuffer;
-
-const TARGET_SIZE: u32 = 30;
 
 pub(crate) struct Transform<'a> {
     task: &'a OCRTask,
+    parameters: &'a Parameters,
     stretched_matrices: HashMap<Transformation, [u32; 32]>,
     image: GrayImage
 }
 
 impl<'a> Transform<'a> {
-    pub(crate) fn new(task: &'a OCRTask) -> Self {
-        let resized_image = stretch_check_ratio(&task.image, TARGET_SIZE, TARGET_SIZE);
+    pub(crate) fn new(task: &'a OCRTask, parameters: &'a Parameters) -> Self {
+        let resized_image = stretch_check_ratio(&task.image, parameters.target_size, parameters.target_size);
         Self {
             task,
+            parameters,
             stretched_matrices: HashMap::new(),
-            image: sharpen_image(&resized_image, None, None)
+            image: resized_image
         }
     }
 
-    pub(crate) fn run(&self, max_translate: i32, max_stretch: i32, max_steps: i32) -> Vec<TargetMatrix> {
+    /// Builds target matrices for every combination of translations and stretches within the
+    /// limits. Returns nothing if the task was cancelled.
+    pub(crate) fn run(&mut self, max_translate: i32, max_stretch: i32, max_steps: i32) -> Vec<TargetMatrix> {
         let mut targets = vec![];
 
+        let max_offset = ((32 - self.parameters.target_size) / 2) as i32;
+
         for ht in -max_translate..=max_translate {
+            // Each horizontal translation is one batch, cancelled tasks stop between batches
+            if self.task.is_cancelled() {
+                log::trace!("transform of request {} cancelled", self.task.request_id);
+                return vec![];
+            }
+
             for vt in -max_translate..=max_translate {
                 for hs in -max_stretch..=max_stretch {
                     for vs in -max_stretch..=max_stretch {
                         if ht.abs() + vt.abs() + hs.abs() + vs.abs() > max_steps { continue; }
-                        if (hs as f32 / 2.0).ceil() as i32 + ht.abs() > ((32 - PARAMETERS.target_size) / 2) as i32 { continue; }
-                        if (vs as f32 / 2.0).ceil() as i32 + vt.abs() > ((32 - PARAMETERS.target_size) / 2) as i32 { continue; }
+                        if (hs as f32 / 2.0).ceil() as i32 + ht.abs() > max_offset { continue; }
+                        if (vs as f32 / 2.0).ceil() as i32 + vt.abs() > max_offset { continue; }
 
                         let parameters = Transformation::new(ht, vt, hs, vs);
-
+                        targets.push(self.transform(parameters));
                     }
                 }
             }
@@ -47,56 +55,39 @@ impl<'a> Transform<'a> {
         targets
     }
 
-    fn transform<I>(&mut self, image: &I, parameters: Transformation) -> TargetMatrix
-    where
-        I: ConvertBuffer<ImageBuffer<<I as GenericImageView>::Pixel, Vec<u8>>> + GenericImage,
-        <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
-    {
-        let mut mx = self.build_matrix(image, &parameters);
-        let halo = build_mx_halo(&mut mx, PARAMETERS.ocr_halo_size - 1);
+    fn transform(&mut self, parameters: Transformation) -> TargetMatrix {
+        let mx = self.build_matrix(&parameters);
+        let halo = build_mx_halo(&mx, self.parameters.ocr_halo_size);
         let pixels = count_bits(&mx);
 
-        let target = TargetMatrix::new(
-            mx.clone(),
+        TargetMatrix::new(
+            mx,
             pixels,
             halo,
             self.task.char_index.unwrap_or(0),
             parameters
-        );
-
-        target
+        )
     }
 
-    fn build_matrix<I>(&mut self, image: &I, parameters: &Transformation) -> [u32; 32]
-    where
-        I: ConvertBuffer<ImageBuffer<<I as GenericImageView>::Pixel, Vec<u8>>> + GenericImage,
-        <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
-    {
-        let mut stretched = self.stretch_image(image, &parameters);
-        Self::translate_matrix(&mut stretched, &parameters);
+    fn build_matrix(&mut self, parameters: &Transformation) -> [u32; 32] {
+        let mut stretched = self.stretch_image(parameters);
+        Self::translate_matrix(&mut stretched, parameters);
         stretched
     }
 
-    fn stretch_image<I>(&mut self, image: &I, parameters: &Transformation) -> [u32; 32]
-    where
-        I: ConvertBuffer<ImageBuffer<<I as GenericImageView>::Pixel, Vec<u8>>> + GenericImage,
-        <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
-    {
+    fn stretch_image(&mut self, parameters: &Transformation) -> [u32; 32] {
         let h_s = parameters.horizontal_stretch;
         let v_s = parameters.vertical_stretch;
 
         let stretch_amount = Transformation::new(0, 0, h_s, v_s);
-        if let Some(stretched) = self.stretched_matrices.get(&parameters) {
-            stretched.clone()
+        if let Some(stretched) = self.stretched_matrices.get(&stretch_amount) {
+            *stretched
         } else {
-            let new_width = PARAMETERS.target_size + h_s as u32;
-            let new_height = PARAMETERS.target_size + v_s as u32;
-
-            let grayscale = stretch(image, new_width, new_height);
-            let square_grayscale = create_square_image(&grayscale, 32);
-            let square_bw = make_bw(&square_grayscale, None);
-            let stretched = build_bit_mx_from_32_image(&square_bw);
-            self.stretched_matrices.insert(stretch_amount, stretched.clone());
+            let new_width = (self.parameters.target_size as i32 + h_s).max(1) as u32;
+            let new_height = (self.parameters.target_size as i32 + v_s).max(1) as u32;
+
+            let stretched = bit_matrix(&self.image, new_width, new_height, self.parameters.pixel_rgba_threshold);
+            self.stretched_matrices.insert(stretch_amount, stretched);
             stretched
         }
     }
@@ -104,4 +95,14 @@ impl<'a> Transform<'a> {
     fn translate_matrix(mx: &mut [u32; 32], parameters: &Transformation) {
         move_matrix(mx, parameters.horizontal_translate, parameters.vertical_translate)
     }
-}
\ No newline at end of file
+}
+
+/// Stretches image to `width` x `height`, centers it in a 32x32 square and binarizes it.
+/// Targets and references go through the same steps so that their matrices line up.
+pub(crate) fn bit_matrix(image: &GrayImage, width: u32, height: u32, black_threshold: u8) -> [u32; 32] {
+    let grayscale = stretch(image, width, height);
+    let square_grayscale = create_square_image(&grayscale, 32);
+    let square_bw = make_bw(&square_grayscale, black_threshold);
+
+    build_bit_mx_from_32_image(&square_bw)
+}
@@ -1,8 +1,19 @@
-use crate::{Orientation, CharacterColor, DictionaryType};
+use crate::{Orientation, CharacterColor, DictionaryType, BinarizationMode, ScaleFilter, PreprocessStep};
+use crate::error::KanjitomoError;
+use crate::presets::Presets;
 use smart_default::SmartDefault;
-use image::Rgba;
+use serde::{Serialize, Deserialize};
+use image::{Rgb, Rgba};
+use std::fs;
+use std::path::Path;
 
-#[derive(Debug, SmartDefault, PartialEq)]
+/// Width and height of the matrices characters are matched in.
+const MATRIX_SIZE: u32 = 32;
+
+/// Settings of a `KanjiTomo` instance. Can be loaded from TOML or JSON configuration files,
+/// fields missing from the file keep their default values.
+#[derive(Debug, Clone, SmartDefault, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Parameters {
    #[default = "data"]
    pub data_dir_name: String,
@@ -28,6 +39,36 @@ pub struct Parameters {
    pub unsharp_threshold: i32,
    #[default = 140]
    pub pixel_rgba_threshold: u8,
+   #[default(_code = "vec![PreprocessStep::Unsharp, PreprocessStep::Screentone, PreprocessStep::Threshold]")]
+   pub preprocessing: Vec<PreprocessStep>,
+   #[default = false]
+   pub screentone_suppression: bool,
+   #[default = 6]
+   pub screentone_max_dot_size: u32,
+   #[default = 2048]
+   pub tile_size: u32,
+   #[default = 64]
+   pub tile_overlap: u32,
+   #[default = false]
+   pub deskew: bool,
+   #[default = 5.0]
+   pub deskew_max_angle: f32,
+   #[default = false]
+   pub upscale: bool,
+   #[default = 16]
+   pub upscale_max_character_size: u32,
+   #[default(ScaleFilter::CatmullRom)]
+   pub upscale_filter: ScaleFilter,
+   #[default(BinarizationMode::Global)]
+   pub binarization_mode: BinarizationMode,
+   #[default = 25]
+   pub adaptive_window_size: u32,
+   #[default = 0.34]
+   pub sauvola_k: f32,
+   #[default(-0.2)]
+   pub niblack_k: f32,
+   #[default = 64]
+   pub otsu_tile_size: u32,
    #[default = false]
    pub fixed_black_level: bool,
    #[default = 0]
@@ -41,12 +82,16 @@ pub struct Parameters {
    #[default = 3]
    pub ocr_halo_size: u32,
    #[default(_code = "Rgba([255, 0, 0, 255])")]
+   #[serde(with = "rgba")]
    pub ocr_target_halo_first_color: Rgba<u8>,
    #[default(_code = "Rgba([255, 175, 175, 255])")]
+   #[serde(with = "rgba")]
    pub ocr_target_halo_last_color: Rgba<u8>,
    #[default(_code = "Rgba([100, 100, 100, 255])")]
+   #[serde(with = "rgba")]
    pub ocr_reference_halo_first_color: Rgba<u8>,
    #[default(_code = "Rgba([195, 195, 195, 255])")]
+   #[serde(with = "rgba")]
    pub ocr_reference_halo_last_color: Rgba<u8>,
    #[default = 4.0]
    pub ocr_black_pixel_score: f32,
@@ -78,6 +123,165 @@ pub struct Parameters {
    pub secondary_dictionary: DictionaryType,
 }
 
+impl Parameters {
+   /// Text color used for binarization when `fixed_black_level` is set.
+   pub fn fixed_black_level_color(&self) -> Rgb<u8> {
+      Rgb([self.fixed_black_level_red, self.fixed_black_level_green, self.fixed_black_level_blue])
+   }
+
+   /// Checks that fields agree with each other and with the matching algorithm. Called when
+   /// `KanjiTomo` is created and when parameters are loaded from a configuration file.
+   pub fn validate(&self) -> Result<(), KanjitomoError> {
+      if self.reference_fonts.len() != self.reference_fonts_bold.len() {
+         return Err(KanjitomoError::FontsMismatch {
+            fonts: self.reference_fonts.len(),
+            bold: self.reference_fonts_bold.len()
+         });
+      }
+
+      if self.ocr_halo_size < 1 {
+         return Err(KanjitomoError::ParameterTooSmall { field: "ocr_halo_size", min: 1 });
+      }
+
+      let halo_scores = [
+         ("ocr_target_halo_scores", &self.ocr_target_halo_scores),
+         ("ocr_reference_halo_scores", &self.ocr_reference_halo_scores),
+      ];
+      for &(field, scores) in halo_scores.iter() {
+         if scores.len() != self.ocr_halo_size as usize {
+            return Err(KanjitomoError::HaloScoresMismatch {
+               field,
+               expected: self.ocr_halo_size as usize,
+               found: scores.len()
+            });
+         }
+      }
+
+      if self.target_size > MATRIX_SIZE {
+         return Err(KanjitomoError::TargetSizeTooLarge { target_size: self.target_size, max: MATRIX_SIZE });
+      }
+
+      if self.target_size < 1 {
+         return Err(KanjitomoError::ParameterTooSmall { field: "target_size", min: 1 });
+      }
+
+      if self.ocr_keep_results_lvl2 > self.ocr_keep_results_lvl1 {
+         return Err(KanjitomoError::KeepResultsMismatch {
+            lvl1: self.ocr_keep_results_lvl1,
+            lvl2: self.ocr_keep_results_lvl2
+         });
+      }
+
+      if self.tile_size > 0 && self.tile_overlap >= self.tile_size {
+         return Err(KanjitomoError::TileOverlapTooLarge { tile_size: self.tile_size, overlap: self.tile_overlap });
+      }
+
+      if self.ocr_threads < 1 {
+         return Err(KanjitomoError::ParameterTooSmall { field: "ocr_threads", min: 1 });
+      }
+
+      Ok(())
+   }
+
+   /// Built-in preset by name, see `Presets`.
+   pub fn preset(name: &str) -> Result<Self, KanjitomoError> {
+      Presets::new().get(name)
+   }
+
+   /// Loads parameters from a configuration file. Files ending with `.json` are read as JSON,
+   /// everything else as TOML.
+   pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, KanjitomoError> {
+      let path = path.as_ref();
+      let contents = fs::read_to_string(path).map_err(KanjitomoError::IOError)?;
+
+      if is_json(path) {
+         Self::from_json(&contents)
+      } else {
+         Self::from_toml(&contents)
+      }
+   }
+
+   /// Writes parameters to a configuration file, format is chosen like in `load`.
+   pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), KanjitomoError> {
+      let path = path.as_ref();
+      let contents = if is_json(path) {
+         self.to_json()?
+      } else {
+         self.to_toml()?
+      };
+
+      fs::write(path, contents).map_err(KanjitomoError::IOError)
+   }
+
+   pub fn from_toml(toml: &str) -> Result<Self, KanjitomoError> {
+      let mut deserializer = toml::Deserializer::new(toml);
+      let parameters: Self = serde_path_to_error::deserialize(&mut deserializer).map_err(config_error)?;
+      parameters.validate().map_err(validation_error)?;
+
+      Ok(parameters)
+   }
+
+   pub fn from_json(json: &str) -> Result<Self, KanjitomoError> {
+      let mut deserializer = serde_json::Deserializer::from_str(json);
+      let parameters: Self = serde_path_to_error::deserialize(&mut deserializer).map_err(config_error)?;
+      deserializer.end().map_err(|e| KanjitomoError::ConfigError { path: ".".to_owned(), message: e.to_string() })?;
+      parameters.validate().map_err(validation_error)?;
+
+      Ok(parameters)
+   }
+
+   pub fn to_toml(&self) -> Result<String, KanjitomoError> {
+      toml::to_string(self).map_err(|e| KanjitomoError::ConfigError { path: ".".to_owned(), message: e.to_string() })
+   }
+
+   pub fn to_json(&self) -> Result<String, KanjitomoError> {
+      serde_json::to_string_pretty(self).map_err(|e| KanjitomoError::ConfigError { path: ".".to_owned(), message: e.to_string() })
+   }
+}
+
+fn is_json(path: &Path) -> bool {
+   path.extension().map_or(false, |extension| extension.eq_ignore_ascii_case("json"))
+}
+
+fn config_error<E: std::fmt::Display>(error: serde_path_to_error::Error<E>) -> KanjitomoError {
+   KanjitomoError::ConfigError {
+      path: error.path().to_string(),
+      message: error.inner().to_string()
+   }
+}
+
+/// Reports `validate` errors of a configuration file at the field that has to be changed.
+fn validation_error(error: KanjitomoError) -> KanjitomoError {
+   let path = match error {
+      KanjitomoError::FontsMismatch { .. } => "reference_fonts_bold",
+      KanjitomoError::HaloScoresMismatch { field, .. } => field,
+      KanjitomoError::TargetSizeTooLarge { .. } => "target_size",
+      KanjitomoError::KeepResultsMismatch { .. } => "ocr_keep_results_lvl2",
+      KanjitomoError::ParameterTooSmall { field, .. } => field,
+      KanjitomoError::TileOverlapTooLarge { .. } => "tile_overlap",
+      _ => "."
+   };
+
+   KanjitomoError::ConfigError {
+      path: path.to_owned(),
+      message: error.to_string()
+   }
+}
+
+/// Colors are written as `[red, green, blue, alpha]`.
+mod rgba {
+   use image::Rgba;
+   use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+   pub(super) fn serialize<S: Serializer>(color: &Rgba<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+      color.0.serialize(serializer)
+   }
+
+   pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rgba<u8>, D::Error> {
+      <[u8; 4]>::deserialize(deserializer).map(Rgba)
+   }
+}
+
 #[cfg(test)]
 mod tests {
    use super::*;
@@ -87,4 +291,110 @@ mod tests {
       let args: Parameters = Default::default();
       assert_eq!(args.reference_fonts, vec!["MS Gothic", "SimSun"])
    }
+
+   #[test]
+   fn test_validate() {
+      assert!(Parameters::default().validate().is_ok());
+
+      let fonts = Parameters { reference_fonts_bold: vec![false], ..Default::default() };
+      match fonts.validate() {
+         Err(KanjitomoError::FontsMismatch { fonts: 2, bold: 1 }) => (),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      let halo = Parameters { ocr_halo_size: 2, ..Default::default() };
+      match halo.validate() {
+         Err(KanjitomoError::HaloScoresMismatch { field: "ocr_target_halo_scores", expected: 2, found: 3 }) => (),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      let target_size = Parameters { target_size: 40, ..Default::default() };
+      match target_size.validate() {
+         Err(KanjitomoError::TargetSizeTooLarge { target_size: 40, max: 32 }) => (),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      let keep_results = Parameters { ocr_keep_results_lvl2: 60, ..Default::default() };
+      match keep_results.validate() {
+         Err(KanjitomoError::KeepResultsMismatch { lvl1: 50, lvl2: 60 }) => (),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      let tile_overlap = Parameters { tile_size: 64, tile_overlap: 64, ..Default::default() };
+      match tile_overlap.validate() {
+         Err(KanjitomoError::TileOverlapTooLarge { tile_size: 64, overlap: 64 }) => (),
+         other => panic!("unexpected result {:?}", other)
+      }
+      assert!(Parameters { tile_size: 0, tile_overlap: 64, ..Default::default() }.validate().is_ok());
+   }
+
+   #[test]
+   fn test_preset() {
+      assert_eq!(Orientation::Horizontal, Parameters::preset("web").unwrap().orientation_target);
+      assert!(Parameters::preset("newspaper").is_err());
+   }
+
+   #[test]
+   fn test_round_trip() {
+      let parameters = Parameters {
+         orientation_target: Orientation::Vertical,
+         preprocessing: vec![PreprocessStep::Unsharp, PreprocessStep::Scale(2), PreprocessStep::Threshold],
+         ocr_target_halo_first_color: Rgba([10, 20, 30, 40]),
+         primary_dictionary: DictionaryType::Chinese("cedict".to_owned()),
+         ..Default::default()
+      };
+
+      assert_eq!(parameters, Parameters::from_toml(&parameters.to_toml().unwrap()).unwrap());
+      assert_eq!(parameters, Parameters::from_json(&parameters.to_json().unwrap()).unwrap());
+   }
+
+   #[test]
+   fn test_partial_override() {
+      let parameters = Parameters::from_toml(r#"
+         vertical = false
+         color_target = "white_on_black"
+         preprocessing = ["threshold", "scale:3"]
+
+         [primary_dictionary]
+         type = "japanese_names"
+         name = "enamdict"
+      "#).unwrap();
+
+      assert!(!parameters.vertical);
+      assert_eq!(CharacterColor::WhiteOnBlack, parameters.color_target);
+      assert_eq!(vec![PreprocessStep::Threshold, PreprocessStep::Scale(3)], parameters.preprocessing);
+      assert_eq!(DictionaryType::JapaneseNames("enamdict".to_owned()), parameters.primary_dictionary);
+      assert_eq!(Parameters::default().target_size, parameters.target_size);
+   }
+
+   #[test]
+   fn test_config_errors() {
+      match Parameters::from_toml("pixel_rgba_threshold = 300") {
+         Err(KanjitomoError::ConfigError { path, .. }) => assert_eq!("pixel_rgba_threshold", path),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      match Parameters::from_json(r#"{ "ocr_target_halo_first_color": [255, 0, 0, 999] }"#) {
+         Err(KanjitomoError::ConfigError { path, .. }) => assert_eq!("ocr_target_halo_first_color[3]", path),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      match Parameters::from_toml("vertcal = true") {
+         Err(KanjitomoError::ConfigError { message, .. }) => assert!(message.contains("vertcal")),
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      match Parameters::from_toml("ocr_keep_results_lvl2 = 60") {
+         Err(KanjitomoError::ConfigError { path, message }) => {
+            assert_eq!("ocr_keep_results_lvl2", path);
+            assert_eq!("ocr_keep_results_lvl2 (60) must not exceed ocr_keep_results_lvl1 (50)", message);
+         },
+         other => panic!("unexpected result {:?}", other)
+      }
+
+      match Parameters::from_json(r#"{ "ocr_halo_size": 2 }"#) {
+         Err(KanjitomoError::ConfigError { path, .. }) => assert_eq!("ocr_target_halo_scores", path),
+         other => panic!("unexpected result {:?}", other)
+      }
+   }
 }
\ No newline at end of file
@@ -0,0 +1,266 @@
+use crate::parameters::Parameters;
+use crate::area::suppress_screentone;
+use crate::util::{binarize, make_bw_fixed, sharpen_image};
+use crate::{PreprocessStep, ScaleFilter};
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+use std::sync::Arc;
+
+/// Image operation run before area detection and character matching. Stages are run in
+/// order by `Pipeline`, each receiving the output of the previous one.
+pub trait PreprocessStage: Send + Sync {
+    fn process(&self, image: DynamicImage) -> DynamicImage;
+}
+
+/// Ordered list of preprocessing stages. Output of the pipeline is treated as black and white,
+/// pixels darker than middle gray are black.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn PreprocessStage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the pipeline listed in `Parameters::preprocessing`. Sharpening shifts colors, so
+    /// it's left out when `fixed_black_level` is set.
+    pub fn from_parameters(parameters: &Arc<Parameters>) -> Self {
+        let mut pipeline = Self::new();
+
+        for step in &parameters.preprocessing {
+            match *step {
+                PreprocessStep::Unsharp => {
+                    if !parameters.fixed_black_level {
+                        pipeline.push(Unsharp {
+                            sigma: parameters.unsharp_sigma,
+                            threshold: parameters.unsharp_threshold,
+                        })
+                    }
+                },
+                PreprocessStep::Screentone => {
+                    if parameters.screentone_suppression {
+                        pipeline.push(Screentone {
+                            max_dot_size: parameters.screentone_max_dot_size,
+                            black_threshold: parameters.pixel_rgba_threshold,
+                        })
+                    }
+                },
+                PreprocessStep::Threshold => pipeline.push(Threshold::new(parameters.clone())),
+                PreprocessStep::Invert => pipeline.push(Invert),
+                PreprocessStep::Denoise => pipeline.push(Denoise { min_neighbours: 1 }),
+                PreprocessStep::Scale(factor) => pipeline.push(Scale {
+                    factor,
+                    filter: parameters.upscale_filter,
+                }),
+            }
+        }
+
+        pipeline
+    }
+
+    /// Builds the pipeline character images are preprocessed with before matching. Only the
+    /// stages of `Parameters::preprocessing` that work on single characters are kept:
+    /// sharpening and inversion. Matching binarizes the stretched character itself, and
+    /// screentone, noise and scale are handled on the whole page.
+    pub fn for_ocr(parameters: &Arc<Parameters>) -> Self {
+        let mut pipeline = Self::new();
+
+        for step in &parameters.preprocessing {
+            match *step {
+                PreprocessStep::Unsharp if !parameters.fixed_black_level => pipeline.push(Unsharp {
+                    sigma: parameters.unsharp_sigma,
+                    threshold: parameters.unsharp_threshold,
+                }),
+                PreprocessStep::Invert => pipeline.push(Invert),
+                _ => ()
+            }
+        }
+
+        pipeline
+    }
+
+    /// Adds stage to the end of the pipeline.
+    pub fn push<S: PreprocessStage + 'static>(&mut self, stage: S) {
+        self.stages.push(Box::new(stage))
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn run(&self, image: DynamicImage) -> DynamicImage {
+        self.stages.iter().fold(image, |image, stage| stage.process(image))
+    }
+}
+
+/// Unsharp mask that makes thin strokes survive thresholding.
+#[derive(Debug, Copy, Clone)]
+pub struct Unsharp {
+    pub sigma: f32,
+    pub threshold: i32,
+}
+
+impl PreprocessStage for Unsharp {
+    fn process(&self, image: DynamicImage) -> DynamicImage {
+        DynamicImage::ImageRgba8(sharpen_image(&image.to_rgba(), self.sigma, self.threshold))
+    }
+}
+
+/// Creates black and white image with `binarization_mode`, or by distance to text color if
+/// `fixed_black_level` is set.
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    parameters: Arc<Parameters>,
+}
+
+impl Threshold {
+    pub fn new(parameters: Arc<Parameters>) -> Self {
+        Self { parameters }
+    }
+}
+
+impl PreprocessStage for Threshold {
+    fn process(&self, image: DynamicImage) -> DynamicImage {
+        let parameters = &*self.parameters;
+
+        let bw = if parameters.fixed_black_level {
+            make_bw_fixed(&image.to_rgb(), parameters.fixed_black_level_color(), parameters.fixed_black_level_range)
+        } else {
+            binarize(&image.to_rgba(), parameters)
+        };
+
+        DynamicImage::ImageLuma8(bw)
+    }
+}
+
+/// Swaps black and white, for light text on dark background.
+#[derive(Debug, Copy, Clone)]
+pub struct Invert;
+
+impl PreprocessStage for Invert {
+    fn process(&self, mut image: DynamicImage) -> DynamicImage {
+        image.invert();
+        image
+    }
+}
+
+/// Removes black pixels that have fewer than `min_neighbours` black neighbours. Meant to be
+/// run after `Threshold`.
+#[derive(Debug, Copy, Clone)]
+pub struct Denoise {
+    pub min_neighbours: u32,
+}
+
+impl PreprocessStage for Denoise {
+    fn process(&self, image: DynamicImage) -> DynamicImage {
+        let image = image.to_luma();
+        let (width, height) = image.dimensions();
+        let is_black = |x: u32, y: u32| image.get_pixel(x, y).0[0] < 128;
+
+        let denoised: GrayImage = ImageBuffer::from_fn(width, height, |x, y| {
+            if !is_black(x, y) {
+                return Luma([255]);
+            }
+
+            let mut neighbours = 0;
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    if (nx, ny) != (x, y) && is_black(nx, ny) {
+                        neighbours += 1;
+                    }
+                }
+            }
+
+            if neighbours < self.min_neighbours {
+                Luma([255])
+            } else {
+                Luma([0])
+            }
+        });
+
+        DynamicImage::ImageLuma8(denoised)
+    }
+}
+
+/// Scales the image by an integer factor. Area coordinates are mapped back to the original
+/// image, so characters are still reported at their original location.
+#[derive(Debug, Copy, Clone)]
+pub struct Scale {
+    pub factor: u32,
+    pub filter: ScaleFilter,
+}
+
+impl PreprocessStage for Scale {
+    fn process(&self, image: DynamicImage) -> DynamicImage {
+        let factor = self.factor.max(1);
+        image.resize_exact(image.width() * factor, image.height() * factor, self.filter.into())
+    }
+}
+
+/// Removes halftone dots, see `screentone_suppression`.
+#[derive(Debug, Copy, Clone)]
+pub struct Screentone {
+    pub max_dot_size: u32,
+    /// Threshold of the preliminary binarization that finds the dots.
+    pub black_threshold: u8,
+}
+
+impl PreprocessStage for Screentone {
+    fn process(&self, image: DynamicImage) -> DynamicImage {
+        let mut image = image.to_rgba();
+        suppress_screentone(&mut image, self.max_dot_size, self.black_threshold);
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bw_image(black: &[(u32, u32)]) -> DynamicImage {
+        DynamicImage::ImageLuma8(ImageBuffer::from_fn(10, 10, |x, y| {
+            if black.contains(&(x, y)) {
+                Luma([0])
+            } else {
+                Luma([255])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_default_pipeline() {
+        let pipeline = Pipeline::from_parameters(&Arc::new(Parameters::default()));
+
+        // Screentone suppression is disabled by default
+        assert_eq!(2, pipeline.len());
+        assert_eq!(1, Pipeline::for_ocr(&Arc::new(Parameters::default())).len());
+        assert!(Pipeline::for_ocr(&Arc::new(Parameters { fixed_black_level: true, ..Default::default() })).is_empty());
+    }
+
+    #[test]
+    fn test_run_in_order() {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Denoise { min_neighbours: 1 });
+        pipeline.push(Invert);
+
+        let image = pipeline.run(bw_image(&[(1, 1), (5, 5), (5, 6)])).to_luma();
+
+        assert_eq!(&Luma([0]), image.get_pixel(1, 1));
+        assert_eq!(&Luma([255]), image.get_pixel(5, 5));
+        assert_eq!(&Luma([0]), image.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn test_scale() {
+        let image = Scale { factor: 2, filter: ScaleFilter::Nearest }.process(bw_image(&[(1, 1)])).to_luma();
+
+        assert_eq!((20, 20), image.dimensions());
+        assert_eq!(&Luma([0]), image.get_pixel(3, 3));
+        assert_eq!(&Luma([255]), image.get_pixel(4, 4));
+    }
+}
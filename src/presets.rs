@@ -0,0 +1,154 @@
+use crate::{CharacterColor, Orientation};
+use crate::error::KanjitomoError;
+use crate::parameters::Parameters;
+use std::collections::HashMap;
+
+/// Named `Parameters` tuned for a kind of content. Built-in presets are `manga`,
+/// `light_novel`, `game`, `web` and `subtitles`, more can be added with `register`.
+#[derive(Debug, Clone)]
+pub struct Presets {
+    presets: HashMap<String, Parameters>,
+}
+
+impl Presets {
+    /// Registry with the built-in presets.
+    pub fn new() -> Self {
+        let mut presets = HashMap::new();
+        presets.insert("manga".to_owned(), manga());
+        presets.insert("light_novel".to_owned(), light_novel());
+        presets.insert("game".to_owned(), game());
+        presets.insert("web".to_owned(), web());
+        presets.insert("subtitles".to_owned(), subtitles());
+
+        Self { presets }
+    }
+
+    /// Adds preset or replaces an existing one with the same name. Parameters are validated
+    /// before they're added.
+    pub fn register<S: Into<String>>(&mut self, name: S, parameters: Parameters) -> Result<(), KanjitomoError> {
+        parameters.validate()?;
+        self.presets.insert(name.into(), parameters);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Parameters, KanjitomoError> {
+        self.presets.get(name)
+            .cloned()
+            .ok_or_else(|| KanjitomoError::UnknownPreset(name.to_owned()))
+    }
+
+    /// Preset names in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+impl Default for Presets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scanned manga pages. Scans are blurry and have screentone around the text.
+fn manga() -> Parameters {
+    Parameters {
+        pixel_rgba_threshold: 140,
+        unsharp_sigma: 4.0,
+        unsharp_threshold: 2,
+        color_target: CharacterColor::Auto,
+        orientation_target: Orientation::Vertical,
+        vertical: true,
+        ocr_max_characters: 8,
+        screentone_suppression: true,
+        ..Default::default()
+    }
+}
+
+/// Printed light novel pages. Clean text in long vertical columns.
+fn light_novel() -> Parameters {
+    Parameters {
+        pixel_rgba_threshold: 150,
+        unsharp_sigma: 2.0,
+        unsharp_threshold: 3,
+        color_target: CharacterColor::BlackOnWhite,
+        orientation_target: Orientation::Vertical,
+        vertical: true,
+        ocr_max_characters: 12,
+        ..Default::default()
+    }
+}
+
+/// Game screenshots. Text boxes can be either light or dark.
+fn game() -> Parameters {
+    Parameters {
+        pixel_rgba_threshold: 160,
+        unsharp_sigma: 3.0,
+        unsharp_threshold: 2,
+        color_target: CharacterColor::Auto,
+        orientation_target: Orientation::Horizontal,
+        vertical: false,
+        ocr_max_characters: 20,
+        ..Default::default()
+    }
+}
+
+/// Web page screenshots. Text is sharp and antialiased, so it's barely sharpened.
+fn web() -> Parameters {
+    Parameters {
+        pixel_rgba_threshold: 170,
+        unsharp_sigma: 1.0,
+        unsharp_threshold: 1,
+        color_target: CharacterColor::BlackOnWhite,
+        orientation_target: Orientation::Horizontal,
+        vertical: false,
+        ocr_max_characters: 25,
+        ..Default::default()
+    }
+}
+
+/// White subtitles on video frames.
+fn subtitles() -> Parameters {
+    Parameters {
+        pixel_rgba_threshold: 110,
+        unsharp_sigma: 2.0,
+        unsharp_threshold: 2,
+        color_target: CharacterColor::WhiteOnBlack,
+        orientation_target: Orientation::Horizontal,
+        vertical: false,
+        ocr_max_characters: 20,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_presets() {
+        let presets = Presets::new();
+
+        assert_eq!(vec!["game", "light_novel", "manga", "subtitles", "web"], presets.names());
+        for name in presets.names() {
+            assert!(presets.get(name).unwrap().validate().is_ok());
+        }
+        assert_eq!(CharacterColor::WhiteOnBlack, presets.get("subtitles").unwrap().color_target);
+    }
+
+    #[test]
+    fn test_custom_preset() {
+        let mut presets = Presets::new();
+        let custom = Parameters { ocr_max_characters: 4, ..presets.get("manga").unwrap() };
+
+        presets.register("four_koma", custom.clone()).unwrap();
+
+        assert_eq!(custom, presets.get("four_koma").unwrap());
+        assert!(presets.register("broken", Parameters { target_size: 40, ..Default::default() }).is_err());
+        match presets.get("broken") {
+            Err(KanjitomoError::UnknownPreset(name)) => assert_eq!("broken", name),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+}
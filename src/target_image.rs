@@ -0,0 +1,150 @@
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Primitive, Rgb, RgbImage, Rgba, RgbaImage};
+use num_traits::ToPrimitive;
+
+pub type Rgb32FImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+pub type Rgba32FImage = ImageBuffer<Rgba<f32>, Vec<f32>>;
+
+/// Image to read characters from. Any bit depth is accepted; images are converted to 8-bit
+/// RGB internally with transparent pixels composited over white. Float channels are
+/// expected to be in range 0.0 - 1.0.
+#[derive(Debug, Clone)]
+pub struct TargetImage {
+    source: Source,
+    premultiplied: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    Dynamic(DynamicImage),
+    Rgb32F(Rgb32FImage),
+    Rgba32F(Rgba32FImage),
+}
+
+impl TargetImage {
+    fn new(source: Source) -> Self {
+        Self { source, premultiplied: false }
+    }
+
+    /// Marks color channels as already multiplied by alpha.
+    pub fn premultiplied(mut self) -> Self {
+        self.premultiplied = true;
+        self
+    }
+
+    /// Converts the image to opaque 8-bit RGBA, the working representation of area detection
+    /// and OCR.
+    pub(crate) fn to_rgba(&self) -> RgbaImage {
+        match self.source {
+            Source::Dynamic(DynamicImage::ImageRgba8(ref image)) => composite(image, 255.0, self.premultiplied),
+            Source::Dynamic(DynamicImage::ImageLumaA8(ref image)) => composite(image, 255.0, self.premultiplied),
+            Source::Dynamic(DynamicImage::ImageLuma16(ref image)) => composite(image, 65535.0, self.premultiplied),
+            Source::Dynamic(DynamicImage::ImageLumaA16(ref image)) => composite(image, 65535.0, self.premultiplied),
+            Source::Dynamic(DynamicImage::ImageRgb16(ref image)) => composite(image, 65535.0, self.premultiplied),
+            Source::Dynamic(DynamicImage::ImageRgba16(ref image)) => composite(image, 65535.0, self.premultiplied),
+            Source::Dynamic(ref image) => composite(&image.to_rgba(), 255.0, self.premultiplied),
+            Source::Rgb32F(ref image) => composite(image, 1.0, self.premultiplied),
+            Source::Rgba32F(ref image) => composite(image, 1.0, self.premultiplied),
+        }
+    }
+}
+
+/// Scales channels to 8 bits and composites transparent pixels over white.
+fn composite<P>(image: &ImageBuffer<P, Vec<P::Subpixel>>, max: f32, premultiplied: bool) -> RgbaImage
+where
+    P: Pixel + 'static,
+    P::Subpixel: Primitive + 'static
+{
+    let channel = |value: P::Subpixel| (value.to_f32().unwrap_or(0.0) / max).max(0.0).min(1.0);
+
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y).to_rgba();
+        let alpha = channel(pixel[3]);
+
+        let mut rgba = [0u8, 0, 0, 255];
+        for i in 0..3 {
+            let value = channel(pixel[i]);
+            let value = if premultiplied {
+                value + (1.0 - alpha)
+            } else {
+                value * alpha + (1.0 - alpha)
+            };
+            rgba[i] = (value.min(1.0) * 255.0).round() as u8;
+        }
+
+        Rgba(rgba)
+    })
+}
+
+impl From<DynamicImage> for TargetImage {
+    fn from(image: DynamicImage) -> Self {
+        Self::new(Source::Dynamic(image))
+    }
+}
+
+impl From<RgbaImage> for TargetImage {
+    fn from(image: RgbaImage) -> Self {
+        Self::new(Source::Dynamic(DynamicImage::ImageRgba8(image)))
+    }
+}
+
+impl From<RgbImage> for TargetImage {
+    fn from(image: RgbImage) -> Self {
+        Self::new(Source::Dynamic(DynamicImage::ImageRgb8(image)))
+    }
+}
+
+impl From<GrayImage> for TargetImage {
+    fn from(image: GrayImage) -> Self {
+        Self::new(Source::Dynamic(DynamicImage::ImageLuma8(image)))
+    }
+}
+
+impl From<ImageBuffer<Luma<u16>, Vec<u16>>> for TargetImage {
+    fn from(image: ImageBuffer<Luma<u16>, Vec<u16>>) -> Self {
+        Self::new(Source::Dynamic(DynamicImage::ImageLuma16(image)))
+    }
+}
+
+impl From<Rgb32FImage> for TargetImage {
+    fn from(image: Rgb32FImage) -> Self {
+        Self::new(Source::Rgb32F(image))
+    }
+}
+
+impl From<Rgba32FImage> for TargetImage {
+    fn from(image: Rgba32FImage) -> Self {
+        Self::new(Source::Rgba32F(image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_16_bit() {
+        let image: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_pixel(2, 2, Luma([32896]));
+        let rgba = TargetImage::from(image).to_rgba();
+
+        assert_eq!(&Rgba([128, 128, 128, 255]), rgba.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn test_float() {
+        let image: Rgb32FImage = ImageBuffer::from_pixel(2, 2, Rgb([0.0, 0.5, 2.0]));
+        let rgba = TargetImage::from(image).to_rgba();
+
+        assert_eq!(&Rgba([0, 128, 255, 255]), rgba.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn test_alpha() {
+        let image: RgbaImage = ImageBuffer::from_pixel(1, 1, Rgba([128, 128, 128, 128]));
+
+        let straight = TargetImage::from(image.clone()).to_rgba();
+        let premultiplied = TargetImage::from(image).premultiplied().to_rgba();
+
+        assert_eq!(&Rgba([191, 191, 191, 255]), straight.get_pixel(0, 0));
+        assert_eq!(&Rgba([255, 255, 255, 255]), premultiplied.get_pixel(0, 0));
+    }
+}
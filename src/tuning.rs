@@ -0,0 +1,347 @@
+use crate::{KanjiTomo, Point};
+use crate::error::KanjitomoError;
+use crate::parameters::Parameters;
+use crate::preprocess::Pipeline;
+use crate::util::is_image;
+use image::RgbaImage;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Image with the text expected at given points.
+pub struct Sample {
+    pub image: RgbaImage,
+    pub labels: Vec<(Point, String)>,
+}
+
+/// Labeled images used to score parameters. Every image in the dataset directory has a text
+/// file with the same name next to it, `page01.png` and `page01.txt`. Each line of the text
+/// file is `x y text`: OCR started from point (x, y) should read `text`.
+pub struct Dataset {
+    pub samples: Vec<Sample>,
+}
+
+impl Dataset {
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, KanjitomoError> {
+        let mut paths: Vec<_> = fs::read_dir(dir.as_ref())
+            .map_err(KanjitomoError::IOError)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_image(path) && path.with_extension("txt").is_file())
+            .collect();
+        paths.sort();
+
+        let mut samples = vec![];
+        for path in paths {
+            let image = image::open(&path).map_err(KanjitomoError::OCRError)?.to_rgba();
+            let labels_path = path.with_extension("txt");
+            let labels = fs::read_to_string(&labels_path).map_err(KanjitomoError::IOError)?;
+
+            samples.push(Sample {
+                image,
+                labels: parse_labels(&labels, &labels_path.display().to_string())?,
+            });
+        }
+
+        Ok(Self { samples })
+    }
+
+    /// Number of expected characters in the whole dataset.
+    pub fn character_count(&self) -> usize {
+        self.samples.iter()
+            .flat_map(|sample| sample.labels.iter())
+            .map(|(_, text)| text.chars().count())
+            .sum()
+    }
+}
+
+fn parse_labels(labels: &str, path: &str) -> Result<Vec<(Point, String)>, KanjitomoError> {
+    let mut parsed = vec![];
+
+    for (index, line) in labels.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message: &str| KanjitomoError::DatasetError {
+            path: path.to_owned(),
+            line: index + 1,
+            message: message.to_owned()
+        };
+
+        let mut parts = line.splitn(3, char::is_whitespace);
+        let x = parts.next().and_then(|x| x.parse().ok()).ok_or_else(|| error("x coordinate is missing"))?;
+        let y = parts.next().and_then(|y| y.parse().ok()).ok_or_else(|| error("y coordinate is missing"))?;
+        let text = parts.next().map(str::trim).filter(|text| !text.is_empty()).ok_or_else(|| error("text is missing"))?;
+
+        parsed.push((Point { x, y }, text.to_owned()));
+    }
+
+    Ok(parsed)
+}
+
+/// Number of character insertions, deletions and substitutions needed to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Parameters field searched by `Tuner`. `apply` sets the field from one of `values`.
+pub struct Dimension {
+    pub name: &'static str,
+    pub values: Vec<f32>,
+    apply: Box<dyn Fn(&mut Parameters, f32)>,
+}
+
+impl Dimension {
+    pub fn new<F: Fn(&mut Parameters, f32) + 'static>(name: &'static str, values: Vec<f32>, apply: F) -> Self {
+        Self { name, values, apply: Box::new(apply) }
+    }
+
+    /// Dimensions searched by default: binarization threshold, unsharp settings, halo scores
+    /// as multiples of the scores in `base` and the number of kept results.
+    pub fn defaults(base: &Parameters) -> Vec<Self> {
+        let target_halo_scores = base.ocr_target_halo_scores.clone();
+        let reference_halo_scores = base.ocr_reference_halo_scores.clone();
+
+        vec![
+            Dimension::new("pixel_rgba_threshold", range(100.0, 200.0, 10.0), |p, v| p.pixel_rgba_threshold = v as u8),
+            Dimension::new("unsharp_sigma", range(1.0, 6.0, 1.0), |p, v| p.unsharp_sigma = v),
+            Dimension::new("unsharp_threshold", range(0.0, 5.0, 1.0), |p, v| p.unsharp_threshold = v as i32),
+            Dimension::new("ocr_target_halo_scores", range(0.5, 1.5, 0.25), move |p, v| {
+                p.ocr_target_halo_scores = target_halo_scores.iter().map(|score| score * v).collect()
+            }),
+            Dimension::new("ocr_reference_halo_scores", range(0.5, 1.5, 0.25), move |p, v| {
+                p.ocr_reference_halo_scores = reference_halo_scores.iter().map(|score| score * v).collect()
+            }),
+            Dimension::new("ocr_keep_results_lvl1", vec![20.0, 30.0, 50.0, 80.0], |p, v| p.ocr_keep_results_lvl1 = v as u8),
+            Dimension::new("ocr_keep_results_lvl2", vec![6.0, 12.0, 20.0], |p, v| p.ocr_keep_results_lvl2 = v as u8),
+        ]
+    }
+}
+
+fn range(start: f32, end: f32, step: f32) -> Vec<f32> {
+    let steps = ((end - start) / step).round() as usize;
+    (0..=steps).map(|i| start + step * i as f32).collect()
+}
+
+/// Best parameters found by `Tuner`.
+#[derive(Debug)]
+pub struct TuningResult {
+    pub parameters: Parameters,
+    /// Character error rate of `parameters` over the whole dataset.
+    pub error_rate: f32,
+    /// Number of parameter combinations that were scored.
+    pub evaluations: usize,
+}
+
+/// Searches parameters that minimize character error rate on a dataset. Dimensions are
+/// searched one at a time while the others are kept at their best value so far, and the
+/// search is repeated until a round brings no improvement. Every evaluation runs on the
+/// same worker threads.
+pub struct Tuner {
+    dataset: Dataset,
+    base: Parameters,
+    dimensions: Vec<Dimension>,
+    max_rounds: usize,
+    references: Vec<(char, RgbaImage)>,
+    /// Instance whose workers evaluations share.
+    workers: KanjiTomo,
+}
+
+impl Tuner {
+    pub fn new(dataset: Dataset, base: Parameters) -> Self {
+        let dimensions = Dimension::defaults(&base);
+        let workers = KanjiTomo::build(Arc::new(base.clone()), Pipeline::new());
+
+        Self {
+            dataset,
+            base,
+            dimensions,
+            max_rounds: 3,
+            references: vec![],
+            workers,
+        }
+    }
+
+    /// Reference characters the dataset is read with, see `read_references`. Reference
+    /// matrices depend on the parameters, so they are rebuilt for every evaluation.
+    pub fn references(mut self, references: Vec<(char, RgbaImage)>) -> Self {
+        self.references = references;
+        self
+    }
+
+    /// Replaces the searched dimensions.
+    pub fn dimensions(mut self, dimensions: Vec<Dimension>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+
+    pub fn max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+
+    pub fn run(&self) -> Result<TuningResult, KanjitomoError> {
+        let mut best = self.base.clone();
+        let mut best_rate = self.error_rate(&best)?;
+        let mut evaluations = 1;
+        log::info!("base error rate is {}", best_rate);
+
+        for round in 0..self.max_rounds {
+            let mut improved = false;
+
+            for dimension in &self.dimensions {
+                for &value in &dimension.values {
+                    let mut candidate = best.clone();
+                    (dimension.apply)(&mut candidate, value);
+                    if candidate == best || candidate.validate().is_err() {
+                        continue;
+                    }
+
+                    let rate = self.error_rate(&candidate)?;
+                    evaluations += 1;
+
+                    if rate < best_rate {
+                        log::info!("round {}: {} = {} lowers error rate to {}", round, dimension.name, value, rate);
+                        best = candidate;
+                        best_rate = rate;
+                        improved = true;
+                    }
+                }
+            }
+
+            if !improved {
+                break;
+            }
+        }
+
+        Ok(TuningResult {
+            parameters: best,
+            error_rate: best_rate,
+            evaluations,
+        })
+    }
+
+    /// Character error rate of `parameters` over the dataset. OCR output is cut to the length
+    /// of the expected text, since reading continues past the labeled text.
+    pub fn error_rate(&self, parameters: &Parameters) -> Result<f32, KanjitomoError> {
+        let mut kanjitomo = KanjiTomo::with_parameters(parameters.clone())?;
+        kanjitomo.share_workers(&self.workers);
+        for (character, image) in &self.references {
+            kanjitomo.add_reference(*character, image.clone());
+        }
+        let mut errors = 0;
+
+        for sample in &self.dataset.samples {
+            kanjitomo.set_target_image(sample.image.clone())?;
+
+            for (point, expected) in &sample.labels {
+                let result = kanjitomo.run_ocr(*point)?;
+                let actual: String = result.search_string.chars().take(expected.chars().count()).collect();
+                errors += edit_distance(expected, &actual);
+            }
+        }
+
+        Ok(errors as f32 / self.dataset.character_count().max(1) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CharacterColor, PreprocessStep};
+    use image::Rgba;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(0, edit_distance("漢字", "漢字"));
+        assert_eq!(1, edit_distance("漢字", "漢子"));
+        assert_eq!(2, edit_distance("腹切り", "腹"));
+        assert_eq!(3, edit_distance("", "かなア"));
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = parse_labels("10 20 漢字\n\n 5 7 ひら がな \n", "page.txt").unwrap();
+
+        assert_eq!(vec![
+            (Point { x: 10, y: 20 }, "漢字".to_owned()),
+            (Point { x: 5, y: 7 }, "ひら がな".to_owned()),
+        ], labels);
+
+        match parse_labels("10 漢字", "page.txt") {
+            Err(KanjitomoError::DatasetError { line: 1, .. }) => (),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_default_dimensions() {
+        let base = Parameters::default();
+        let dimensions = Dimension::defaults(&base);
+        let halo = dimensions.iter().find(|d| d.name == "ocr_target_halo_scores").unwrap();
+
+        let mut parameters = base.clone();
+        (halo.apply)(&mut parameters, 2.0);
+        (halo.apply)(&mut parameters, 0.5);
+
+        assert_eq!(vec![-0.5, -2.5, -6.0], parameters.ocr_target_halo_scores);
+        assert_eq!(vec![100.0, 110.0, 120.0], range(100.0, 120.0, 10.0));
+    }
+
+    /// 24x24 character drawn from 3 pixel wide strokes, `(x, y, width, height)` in stroke units.
+    fn glyph(strokes: &[(u32, u32, u32, u32)], color: Rgba<u8>) -> RgbaImage {
+        RgbaImage::from_fn(24, 24, |x, y| {
+            let inside = strokes.iter().any(|&(sx, sy, width, height)| {
+                x >= sx * 3 && x < (sx + width) * 3 && y >= sy * 3 && y < (sy + height) * 3
+            });
+            if inside { color } else { Rgba([255, 255, 255, 255]) }
+        })
+    }
+
+    #[test]
+    fn test_run() {
+        let cross = [(0, 3, 8, 2), (3, 0, 2, 8)];
+        let square = [(0, 0, 8, 1), (0, 7, 8, 1), (0, 0, 1, 8), (7, 0, 1, 8)];
+        let black = Rgba([0, 0, 0, 255]);
+        let gray = Rgba([150, 150, 150, 255]);
+
+        // Gray column of 十 and 口, which is only black above threshold 150
+        let mut image = RgbaImage::from_pixel(60, 90, Rgba([255, 255, 255, 255]));
+        image::imageops::replace(&mut image, &glyph(&cross, gray), 18, 10);
+        image::imageops::replace(&mut image, &glyph(&square, gray), 18, 44);
+        let dataset = Dataset {
+            samples: vec![Sample { image, labels: vec![(Point { x: 30, y: 22 }, "十口".to_owned())] }]
+        };
+
+        let base = Parameters {
+            pixel_rgba_threshold: 100,
+            preprocessing: vec![PreprocessStep::Threshold],
+            color_target: CharacterColor::BlackOnWhite,
+            ..Default::default()
+        };
+        let tuner = Tuner::new(dataset, base)
+            .references(vec![('十', glyph(&cross, black)), ('口', glyph(&square, black)), ('一', glyph(&[(0, 3, 8, 2)], black))])
+            .dimensions(vec![Dimension::new("pixel_rgba_threshold", vec![100.0, 150.0, 200.0], |p, v| p.pixel_rgba_threshold = v as u8)]);
+
+        let result = tuner.run().unwrap();
+
+        assert_eq!(200, result.parameters.pixel_rgba_threshold);
+        assert_eq!(0.0, result.error_rate);
+        // Base, two values in the first round and two in the round without improvement
+        assert_eq!(5, result.evaluations);
+    }
+}
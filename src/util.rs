@@ -13,10 +13,20 @@ use image::imageops::{FilterType, dither, BiLevel};
 use crate::error::KanjitomoError;
 use image::math::utils::clamp;
 use imageproc::drawing::draw_filled_rect_mut;
-use crate::PARAMETERS;
+use crate::BinarizationMode;
+use crate::parameters::Parameters;
 use crate::util::matrix_util::is_bit_set;
 use image::buffer::ConvertBuffer;
 use nalgebra::base::DMatrix;
+use std::path::Path;
+
+const IMAGE_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "bmp"];
+
+pub(crate) fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| IMAGE_EXTENSIONS.iter().any(|e| extension.eq_ignore_ascii_case(e)))
+}
 
 pub(crate) fn contains_pixel(rgb: u32, black_threshold: u8) -> bool {
     let red = ((rgb & 0x00ff0000) >> 16) < black_threshold as u32;
@@ -57,20 +67,14 @@ where
 
 pub(crate) fn sharpen_image<I, P>(
     img: &I,
-    sigma: Option<f32>,
-    threshold: Option<i32>,
+    sigma: f32,
+    threshold: i32,
 ) -> ImageBuffer<P, Vec<u8>>
 where
     I: GenericImage<Pixel = P>,
     P: Pixel<Subpixel = u8> + 'static,
 {
-    log::debug!("{}",PARAMETERS.unsharp_sigma);
-    log::debug!("{}", PARAMETERS.unsharp_threshold);
-    image::imageops::unsharpen(
-        img,
-        sigma.unwrap_or(PARAMETERS.unsharp_sigma),
-        threshold.unwrap_or(PARAMETERS.unsharp_threshold),
-    )
+    image::imageops::unsharpen(img, sigma, threshold)
 }
 
 pub(crate) fn crop<I, P>(img: &I, rect: image::math::Rect) -> SubImage<&I>
@@ -112,15 +116,16 @@ pub(crate) fn matrix_from_image(img: &GrayImage) -> DMatrix<bool>
     DMatrix::from_row_slice(height as usize, width as usize, &mx.into_iter().flatten().collect::<Vec<bool>>()[..])
 }
 
-// build 32x32 matrix from 32x32 image
+// build 32x32 matrix from 32x32 black and white image, black pixels are set bits and the
+// leftmost pixel is the highest bit like in `matrix_util::is_bit_set`
 pub(crate) fn build_bit_mx_from_32_image(image: &GrayImage) -> [u32; 32]
 {
     let mut mx = [0u32; 32];
 
     for y in 0..32 {
         for x in 0..32 {
-            if image.get_pixel(x, y) == &Luma(*named::WHITE.as_raw()) {
-                mx[y as usize].set_bit(x as usize, true);
+            if image.get_pixel(x, y) == &Luma([0]) {
+                mx[y as usize] |= 1 << (31 - x);
             }
         }
     }
@@ -128,7 +133,7 @@ pub(crate) fn build_bit_mx_from_32_image(image: &GrayImage) -> [u32; 32]
     mx
 }
 
-pub(crate) fn make_bw<I>(img: &I, black_threshold: Option<u8>) -> GrayImage
+pub(crate) fn make_bw<I>(img: &I, black_threshold: u8) -> GrayImage
 where
     I: GenericImage,
     <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
@@ -136,7 +141,7 @@ where
     let mut bw_image = image::imageops::grayscale(img);;
 
     for (x, y, p) in img.pixels() {
-        let pixel = contains_pixel(u32::from_le_bytes(p.to_rgba().0), black_threshold.unwrap_or(PARAMETERS.pixel_rgba_threshold));
+        let pixel = contains_pixel(u32::from_le_bytes(p.to_rgba().0), black_threshold);
         if pixel {
             bw_image.put_pixel(x, y, Luma([0]));
         } else {
@@ -147,6 +152,59 @@ where
     bw_image
 }
 
+/// Creates black and white image with the method selected by `binarization_mode`.
+pub(crate) fn binarize<I>(img: &I, parameters: &Parameters) -> GrayImage
+where
+    I: GenericImage,
+    <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
+{
+    match parameters.binarization_mode {
+        BinarizationMode::Global => make_bw(img, parameters.pixel_rgba_threshold),
+        BinarizationMode::Sauvola => {
+            threshold::sauvola(&image::imageops::grayscale(img), parameters.adaptive_window_size, parameters.sauvola_k)
+        },
+        BinarizationMode::Niblack => {
+            threshold::niblack(&image::imageops::grayscale(img), parameters.adaptive_window_size, parameters.niblack_k)
+        },
+        BinarizationMode::Otsu => {
+            threshold::otsu(&image::imageops::grayscale(img), parameters.otsu_tile_size, parameters.pixel_rgba_threshold)
+        }
+    }
+}
+
+/// Marks pixels within `range` RGB distance of the text `color` as black. Used instead of
+/// `make_bw` for colored text where the luminance threshold doesn't separate characters
+/// from the background.
+pub(crate) fn make_bw_fixed<I>(img: &I, color: Rgb<u8>, range: u32) -> GrayImage
+where
+    I: GenericImage,
+    <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + 'static
+{
+    let mut bw_image = ImageBuffer::new(img.width(), img.height());
+
+    for (x, y, p) in img.pixels() {
+        if contains_pixel_fixed(p.to_rgb(), color, range) {
+            bw_image.put_pixel(x, y, Luma([0]));
+        } else {
+            bw_image.put_pixel(x, y, Luma([255]));
+        }
+    }
+
+    bw_image
+}
+
+pub(crate) fn contains_pixel_fixed(pixel: Rgb<u8>, color: Rgb<u8>, range: u32) -> bool {
+    let distance_sq: u32 = pixel.0.iter()
+        .zip(color.0.iter())
+        .map(|(&a, &b)| {
+            let diff = a as i32 - b as i32;
+            (diff * diff) as u32
+        })
+        .sum();
+
+    distance_sq <= range * range
+}
+
 pub(crate) fn build_image_from_32bit_mx(mx: &[u32; 32]) -> GrayImage
 {
     let mut image = ImageBuffer::new(32 ,32);
@@ -195,7 +253,7 @@ where
     <I as GenericImageView>::Pixel: Pixel<Subpixel = u8> + FromColor<Rgba<u8>> + 'static
 {
     let (width, height) = img.dimensions();
-    let mut ratio = (width / height) as f32;
+    let mut ratio = width as f32 / height as f32;
     if ratio > 1.0_f32 {
         ratio = 1_f32 / ratio;
     }
@@ -203,7 +261,7 @@ where
     let mut target_height = target_size;
     let mut target_width = target_size;
 
-    let target_min_dim = scale(ratio, 0.1, 0.4, 8 as f32, target_size as f32).unwrap();
+    let target_min_dim = scale(ratio, 0.1, 0.4, 8 as f32, target_size as f32).unwrap().max(1);
 
     if width > height {
         target_height = target_min_dim
@@ -225,8 +283,8 @@ where
 
     let mut block_image = create_white_image(size, size);
 
-    let delta_x = (size - width) / 2;
-    let delta_y = (size - height) / 2;
+    let delta_x = size.saturating_sub(width) / 2;
+    let delta_y = size.saturating_sub(height) / 2;
 
     for y in 0..height {
         let target_y = y + delta_y;
@@ -239,7 +297,7 @@ where
                 continue;
             }
             let pixel = source_img.get_pixel(x, y);
-            block_image.put_pixel(x, y, pixel)
+            block_image.put_pixel(target_x, target_y, pixel)
         }
     }
 
@@ -265,7 +323,7 @@ pub(crate) fn scale(mut source_value: f32, min_source_value: f32, max_source_val
     }
 
     let source_value = clamp(source_value, min_source_value, max_source_value);
-    let scale = (source_value - min_source_value) / (max_source_value - source_value);
+    let scale = (source_value - min_source_value) / (max_source_value - min_source_value);
 
     let res = target_1 * (1_f32 - scale) + target_2 * scale;
 
@@ -275,16 +333,19 @@ pub(crate) fn scale(mut source_value: f32, min_source_value: f32, max_source_val
 pub(crate) mod matrix_util {
     use std::ops::Sub;
 
+    /// Moves matrix `h` pixels right and `v` pixels down. Pixels moved outside are lost.
     pub(crate) fn move_matrix(mx: &mut [u32; 32], h: i32, v: i32) {
-        for y in 0_i32..mx.len() as i32 {
-            let new_y = y + v;
-            if new_y < 0 || new_y > 31 { continue ;}
+        let source = *mx;
 
-            if h >= 0 {
-                mx[new_y as usize] = mx[y as usize] >> h as u32
+        for y in 0_i32..mx.len() as i32 {
+            let source_y = y - v;
+            mx[y as usize] = if source_y < 0 || source_y > 31 {
+                0
+            } else if h >= 0 {
+                source[source_y as usize].checked_shr(h as u32).unwrap_or(0)
             } else {
-                mx[new_y as usize] = mx[y as usize] << (-1 * h) as u32;
-            }
+                source[source_y as usize].checked_shl((-1 * h) as u32).unwrap_or(0)
+            };
         }
     }
 
@@ -323,59 +384,286 @@ pub(crate) mod matrix_util {
         }
     }
 
-    pub(crate) fn build_mx_halo(mx: &mut [u32; 32], layers: u32) -> Vec<[u32; 32]> {
-        let mut halo = vec![];
-        let mut cloned_mx = None;
-
-        if layers > 1 {
-            cloned_mx = Some(mx.clone());
-        }
+    /// Rings of pixels around the matrix, first layer touches the black pixels and each
+    /// following layer touches the previous one.
+    pub(crate) fn build_mx_halo(mx: &[u32; 32], layers: u32) -> Vec<[u32; 32]> {
+        let mut covered = *mx;
+        let mut halo = Vec::with_capacity(layers as usize);
 
-        for i in 1..=layers {
+        for _ in 0..layers {
             let mut layer = [0u32; 32];
             for y in 0..32 {
-                for x in 0..32 {
-                    if is_halo_bit(x, y, &cloned_mx.unwrap_or(mx.clone())) {
-                        layer[y as usize] |= 1;
-                    }
-                    if x < 31 {
-                        layer[y as usize] <<= 1;
-                    }
+                let mut grown = 0;
+                for row in y.saturating_sub(1)..=(y + 1).min(31) {
+                    grown |= covered[row] | covered[row] << 1 | covered[row] >> 1;
                 }
+                layer[y] = grown & !covered[y];
             }
+
+            add_bits(&layer, &mut covered);
             halo.push(layer);
-            if i < layers {
-                for y in 0..32 {
-                    mx[y] |= layer[y];
+        }
+
+        halo
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_count_bits() {
+            assert_eq!(6, count_bits_num(0b111110001))
+        }
+
+        #[test]
+        fn test_move_matrix() {
+            let mut mx = [0u32; 32];
+            mx[0] = 1 << 31;
+            mx[5] = 1 << 20;
+
+            move_matrix(&mut mx, 2, 1);
+
+            assert!(is_bit_set(2, 1, &mx));
+            assert!(is_bit_set(13, 6, &mx));
+            assert_eq!(2, count_bits(&mx));
+
+            move_matrix(&mut mx, -3, -2);
+            assert!(is_bit_set(10, 4, &mx));
+            assert_eq!(1, count_bits(&mx));
+        }
+
+        #[test]
+        fn test_build_mx_halo() {
+            let mut mx = [0u32; 32];
+            mx[0] = 1 << 31;
+
+            let halo = build_mx_halo(&mx, 2);
+
+            assert_eq!(2, halo.len());
+            assert_eq!(3, count_bits(&halo[0]));
+            assert!(is_bit_set(1, 1, &halo[0]));
+            assert_eq!(5, count_bits(&halo[1]));
+            assert!(is_bit_set(2, 2, &halo[1]));
+            assert!(!is_bit_set(0, 0, &halo[1]));
+            assert_eq!(1, count_bits(&mx));
+        }
+    }
+}
+
+pub(crate) mod threshold {
+    use image::{GrayImage, ImageBuffer, Luma};
+
+    /// Dynamic range of standard deviation in Sauvola's formula.
+    const SAUVOLA_R: f64 = 128.0;
+    /// Tiles with smaller difference between the darkest and the lightest pixel don't contain
+    /// text edges and are thresholded with the global threshold instead of Otsu's level.
+    const MIN_TILE_CONTRAST: usize = 64;
+
+    /// Sums and squared sums of pixel values, so that mean and deviation of any window can be
+    /// calculated in constant time.
+    struct IntegralImage {
+        width: usize,
+        sums: Vec<f64>,
+        squares: Vec<f64>,
+    }
+
+    impl IntegralImage {
+        fn new(image: &GrayImage) -> Self {
+            let width = image.width() as usize + 1;
+            let height = image.height() as usize + 1;
+            let mut sums = vec![0.0; width * height];
+            let mut squares = vec![0.0; width * height];
+
+            for y in 1..height {
+                let mut row_sum = 0.0;
+                let mut row_squares = 0.0;
+                for x in 1..width {
+                    let value = image.get_pixel(x as u32 - 1, y as u32 - 1).0[0] as f64;
+                    row_sum += value;
+                    row_squares += value * value;
+                    sums[y * width + x] = sums[(y - 1) * width + x] + row_sum;
+                    squares[y * width + x] = squares[(y - 1) * width + x] + row_squares;
                 }
             }
+
+            Self { width, sums, squares }
         }
 
-        halo
+        /// Mean and standard deviation of pixels between (x0, y0) inclusive and (x1, y1) exclusive.
+        fn stats(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> (f64, f64) {
+            let w = self.width;
+            let count = ((x1 - x0) * (y1 - y0)) as f64;
+            let sum = self.sums[y1 * w + x1] - self.sums[y0 * w + x1] - self.sums[y1 * w + x0] + self.sums[y0 * w + x0];
+            let squares = self.squares[y1 * w + x1] - self.squares[y0 * w + x1] - self.squares[y1 * w + x0] + self.squares[y0 * w + x0];
+
+            let mean = sum / count;
+            let variance = (squares / count - mean * mean).max(0.0);
+
+            (mean, variance.sqrt())
+        }
     }
 
-    pub(crate) fn is_halo_bit(x: u32, y: u32, mx: &[u32; 32]) -> bool {
-        if is_bit_set(x, y, mx) {
-            return true;
-        } else {
-            for y2 in (y - 1)..=(y + 1) {
-                for x2 in (x - 1)..=(x + 1) {
-                    if is_bit_set(x2, y2, mx) {
-                        return true;
+    pub(crate) fn sauvola(image: &GrayImage, window_size: u32, k: f32) -> GrayImage {
+        let k = k as f64;
+        local(image, window_size, |mean, deviation| mean * (1.0 + k * (deviation / SAUVOLA_R - 1.0)))
+    }
+
+    pub(crate) fn niblack(image: &GrayImage, window_size: u32, k: f32) -> GrayImage {
+        let k = k as f64;
+        local(image, window_size, |mean, deviation| mean + k * deviation)
+    }
+
+    /// Marks pixels darker than the threshold calculated from the window around them as black.
+    fn local<F>(image: &GrayImage, window_size: u32, threshold: F) -> GrayImage
+    where
+        F: Fn(f64, f64) -> f64
+    {
+        let integral = IntegralImage::new(image);
+        let (width, height) = image.dimensions();
+        let half = window_size / 2;
+
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let x0 = x.saturating_sub(half) as usize;
+            let y0 = y.saturating_sub(half) as usize;
+            let x1 = (x + half + 1).min(width) as usize;
+            let y1 = (y + half + 1).min(height) as usize;
+
+            let (mean, deviation) = integral.stats(x0, y0, x1, y1);
+            if (image.get_pixel(x, y).0[0] as f64) < threshold(mean, deviation) {
+                Luma([0])
+            } else {
+                Luma([255])
+            }
+        })
+    }
+
+    /// Thresholds every tile with its own Otsu's level. Tiles without enough contrast use
+    /// `fallback` level.
+    pub(crate) fn otsu(image: &GrayImage, tile_size: u32, fallback: u8) -> GrayImage {
+        let (width, height) = image.dimensions();
+        let tile_size = tile_size.max(1);
+        let mut bw_image = ImageBuffer::new(width, height);
+
+        for tile_y in (0..height).step_by(tile_size as usize) {
+            for tile_x in (0..width).step_by(tile_size as usize) {
+                let x1 = (tile_x + tile_size).min(width);
+                let y1 = (tile_y + tile_size).min(height);
+
+                let mut histogram = [0u32; 256];
+                for y in tile_y..y1 {
+                    for x in tile_x..x1 {
+                        histogram[image.get_pixel(x, y).0[0] as usize] += 1;
+                    }
+                }
+
+                let level = otsu_level(&histogram).unwrap_or(fallback);
+                for y in tile_y..y1 {
+                    for x in tile_x..x1 {
+                        if image.get_pixel(x, y).0[0] < level {
+                            bw_image.put_pixel(x, y, Luma([0]));
+                        } else {
+                            bw_image.put_pixel(x, y, Luma([255]));
+                        }
                     }
                 }
             }
         }
-        return false;
+
+        bw_image
+    }
+
+    /// Level that maximizes between-class variance, pixels darker than the level are black.
+    /// Returns `None` if the histogram doesn't have enough contrast to contain text.
+    fn otsu_level(histogram: &[u32; 256]) -> Option<u8> {
+        let min = histogram.iter().position(|&count| count > 0)?;
+        let max = histogram.iter().rposition(|&count| count > 0)?;
+        if max - min < MIN_TILE_CONTRAST {
+            return None;
+        }
+
+        let total: f64 = histogram.iter().map(|&count| count as f64).sum();
+        let total_sum: f64 = histogram.iter().enumerate().map(|(i, &count)| i as f64 * count as f64).sum();
+
+        let mut dark_weight = 0.0;
+        let mut dark_sum = 0.0;
+        let mut best_variance = 0.0;
+        let mut best_level = min;
+
+        for level in min..max {
+            dark_weight += histogram[level] as f64;
+            dark_sum += level as f64 * histogram[level] as f64;
+            let light_weight = total - dark_weight;
+            if dark_weight == 0.0 || light_weight == 0.0 {
+                continue;
+            }
+
+            let dark_mean = dark_sum / dark_weight;
+            let light_mean = (total_sum - dark_sum) / light_weight;
+            let variance = dark_weight * light_weight * (dark_mean - light_mean).powi(2);
+            if variance > best_variance {
+                best_variance = variance;
+                best_level = level;
+            }
+        }
+
+        Some((best_level + 1) as u8)
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
 
+        /// Gradient background from 100 to 218 with a dark square on the left and a lighter
+        /// square on the right.
+        fn uneven_image() -> GrayImage {
+            ImageBuffer::from_fn(60, 20, |x, y| {
+                if x >= 10 && x < 14 && y >= 8 && y < 12 {
+                    Luma([30])
+                } else if x >= 50 && x < 54 && y >= 8 && y < 12 {
+                    Luma([110])
+                } else {
+                    Luma([100 + 2 * x as u8])
+                }
+            })
+        }
+
         #[test]
-        fn test_count_bits() {
-            assert_eq!(6, count_bits_num(0b111110001))
+        fn test_sauvola() {
+            let bw = sauvola(&uneven_image(), 25, 0.34);
+
+            assert_eq!(&Luma([255]), bw.get_pixel(2, 2));
+            assert_eq!(&Luma([255]), bw.get_pixel(30, 2));
+            assert_eq!(&Luma([0]), bw.get_pixel(12, 10));
+            assert_eq!(&Luma([0]), bw.get_pixel(52, 10));
+        }
+
+        #[test]
+        fn test_otsu() {
+            // Left half is darker than the global threshold
+            let image = ImageBuffer::from_fn(60, 20, |x, y| {
+                let background = if x < 30 { 110 } else { 210 };
+                let text = (x >= 10 && x < 14 || x >= 40 && x < 44) && y >= 8 && y < 12;
+                if text {
+                    Luma([background - 80])
+                } else {
+                    Luma([background])
+                }
+            });
+            let bw = otsu(&image, 30, 140);
+
+            assert_eq!(&Luma([255]), bw.get_pixel(2, 2));
+            assert_eq!(&Luma([255]), bw.get_pixel(58, 2));
+            assert_eq!(&Luma([0]), bw.get_pixel(12, 10));
+            assert_eq!(&Luma([0]), bw.get_pixel(42, 10));
+        }
+
+        #[test]
+        fn test_otsu_low_contrast() {
+            let image = ImageBuffer::from_fn(10, 10, |x, _| Luma([200 + x as u8]));
+            let bw = otsu(&image, 10, 140);
+
+            assert!(bw.pixels().all(|p| p == &Luma([255])));
         }
     }
 }
@@ -462,6 +750,35 @@ pub(crate) mod tests {
         assert_eq!(true, is_kana('へ'));
     }
 
+    #[test]
+    fn test_make_bw_fixed() {
+        let mut image: RgbImage = ImageBuffer::new(4, 1);
+        image.put_pixel(0, 0, Rgb([255, 220, 0]));
+        image.put_pixel(1, 0, Rgb([240, 205, 25]));
+        image.put_pixel(2, 0, Rgb([255, 255, 255]));
+        image.put_pixel(3, 0, Rgb([0, 0, 80]));
+
+        let bw = make_bw_fixed(&image, Rgb([255, 220, 0]), 45);
+
+        assert_eq!(&Luma([0]), bw.get_pixel(0, 0));
+        assert_eq!(&Luma([0]), bw.get_pixel(1, 0));
+        assert_eq!(&Luma([255]), bw.get_pixel(2, 0));
+        assert_eq!(&Luma([255]), bw.get_pixel(3, 0));
+    }
+
+    #[test]
+    fn test_create_square_image() {
+        let image: GrayImage = ImageBuffer::from_pixel(2, 4, Luma([0]));
+
+        let square = create_square_image(&image, 8);
+
+        assert_eq!((8, 8), square.dimensions());
+        assert_eq!(&Luma([0]), square.get_pixel(3, 2));
+        assert_eq!(&Luma([0]), square.get_pixel(4, 5));
+        assert_eq!(&Luma([255]), square.get_pixel(0, 0));
+        assert_eq!(&Luma([255]), square.get_pixel(5, 2));
+    }
+
     //#[test]
     fn test_rect_image() {
         let mut image: RgbaImage = ImageBuffer::new(128, 128);